@@ -0,0 +1,395 @@
+//! Protocollo WebSocket di GameCall, condiviso da server e app Tauri.
+//!
+//! Le definizioni TypeScript in `src/types/protocol/` sono generate da questi tipi
+//! con `cargo test` (vedi `.cargo/config.toml`): non modificarle a mano.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+/// Versione del protocollo parlata da questa build
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Versione più vecchia ancora accettata nell'handshake
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Funzionalità annunciate dal server nel `welcome`
+pub const CAPABILITIES: &[&str] = &["presence", "calls", "webrtc_relay", "resume", "heartbeat", "messages"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "lowercase")]
+#[ts(export)]
+pub enum CallType {
+    Audio,
+    Video,
+    Screen,
+}
+
+impl CallType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Audio => "audio",
+            Self::Video => "video",
+            Self::Screen => "screen",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct CustomStatus {
+    pub text: Option<String>,
+    pub emoji: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl CustomStatus {
+    /// Attivo finché non scade (senza scadenza resta attivo)
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// Attività in corso (gioco)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Activity {
+    pub game: String,
+    pub started_at: DateTime<Utc>,
+    pub party_size: Option<u32>,
+}
+
+/// Messaggio diretto tra due amici, salvato sul server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct DirectMessage {
+    pub id: String,
+    pub sender_id: String,
+    pub recipient_id: String,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+/// Codici dei frame `error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[ts(export)]
+pub enum ErrorCode {
+    /// JSON non valido, `type` mancante o campi errati
+    MalformedMessage,
+    /// `type` sconosciuto o non inviabile dal client
+    UnsupportedMessage,
+    /// Versione di `hello` non più supportata (la connessione viene chiusa)
+    UnsupportedProtocolVersion,
+    /// Primo frame diverso da `hello` o handshake non ricevuto in tempo (la connessione viene chiusa)
+    HelloRequired,
+    NotFriends,
+    RecipientOffline,
+    PayloadTooLarge,
+    RateLimited,
+    ResumeFailed,
+    /// `call_invite` mentre si ha già una chiamata in corso
+    CallerBusy,
+}
+
+/// Messaggi inviati dal client al server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum ClientMessage {
+    // Primo messaggio dopo la connessione (obbligatorio); il server risponde con `welcome`
+    #[serde(rename = "hello")]
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        #[ts(optional)]
+        capabilities: Option<Vec<String>>,
+    },
+    // Ripresa dopo una riconnessione: senza last_seq si usa l'ultimo ack della sessione
+    #[serde(rename = "resume")]
+    Resume {
+        #[serde(default)]
+        #[ts(optional, type = "number | null")]
+        last_seq: Option<i64>,
+    },
+    #[serde(rename = "ack")]
+    Ack {
+        #[ts(type = "number")]
+        seq: i64,
+    },
+    #[serde(rename = "webrtc_signal")]
+    WebRTCSignal {
+        to_user_id: String,
+        signal: serde_json::Value,
+    },
+    #[serde(rename = "call_invite")]
+    CallInvite {
+        to_user_id: String,
+        call_type: CallType,
+    },
+    #[serde(rename = "call_accept")]
+    CallAccept {
+        call_id: String,
+    },
+    #[serde(rename = "call_reject")]
+    CallReject {
+        call_id: String,
+    },
+    #[serde(rename = "call_cancel")]
+    CallCancel {
+        call_id: String,
+    },
+    #[serde(rename = "call_end")]
+    CallEnd {
+        call_id: String,
+    },
+    #[serde(rename = "ping")]
+    Ping,
+}
+
+impl ClientMessage {
+    /// Valori di `type` accettati dal server
+    pub const TYPES: &'static [&'static str] = &[
+        "hello",
+        "resume",
+        "ack",
+        "webrtc_signal",
+        "call_invite",
+        "call_accept",
+        "call_reject",
+        "call_cancel",
+        "call_end",
+        "ping",
+    ];
+
+    /// Decodifica un frame di testo; in caso di errore restituisce il frame
+    /// `error` da rimandare al client
+    #[allow(clippy::result_large_err)] // l'errore è già il frame pronto da inviare
+    pub fn parse(text: &str) -> Result<Self, ServerMessage> {
+        let value: serde_json::Value = serde_json::from_str(text)
+            .map_err(|e| ServerMessage::error(ErrorCode::MalformedMessage, format!("Invalid JSON: {}", e)))?;
+
+        let Some(kind) = value.get("type").and_then(serde_json::Value::as_str) else {
+            return Err(ServerMessage::error(ErrorCode::MalformedMessage, "Missing message type"));
+        };
+
+        if !Self::TYPES.contains(&kind) {
+            return Err(ServerMessage::error(
+                ErrorCode::UnsupportedMessage,
+                format!("Unsupported message type: {}", kind),
+            ));
+        }
+
+        let kind = kind.to_string();
+        serde_json::from_value(value)
+            .map_err(|e| ServerMessage::error(ErrorCode::MalformedMessage, format!("Invalid {} message: {}", kind, e)))
+    }
+}
+
+/// Messaggi inviati dal server al client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum ServerMessage {
+    // Risposta a `hello`: versione negoziata e funzionalità del server
+    #[serde(rename = "welcome")]
+    Welcome {
+        protocol_version: u32,
+        capabilities: Vec<String>,
+    },
+    #[serde(rename = "friend_added")]
+    FriendAdded {
+        friend_id: String,
+        friend_username: String,
+        friend_code: String,
+    },
+    #[serde(rename = "friend_request")]
+    FriendRequest {
+        from_user_id: String,
+        from_username: String,
+        from_friend_code: String,
+        from_avatar_url: Option<String>,
+    },
+    #[serde(rename = "friend_request_accepted")]
+    FriendRequestAccepted {
+        friend_id: String,
+        friend_username: String,
+        friend_code: String,
+    },
+    #[serde(rename = "friend_request_rejected")]
+    FriendRequestRejected {
+        user_id: String,
+    },
+    #[serde(rename = "friend_request_cancelled")]
+    FriendRequestCancelled {
+        from_user_id: String,
+    },
+    #[serde(rename = "friend_removed")]
+    FriendRemoved {
+        friend_id: String,
+    },
+    #[serde(rename = "user_online")]
+    UserOnline {
+        user_id: String,
+    },
+    #[serde(rename = "user_offline")]
+    UserOffline {
+        user_id: String,
+    },
+    #[serde(rename = "presence_update")]
+    PresenceUpdate {
+        user_id: String,
+        #[ts(type = "'online' | 'offline' | 'idle' | 'dnd' | 'in-call'")]
+        status: String,
+        custom_status: Option<CustomStatus>,
+        activity: Option<Activity>,
+    },
+    #[serde(rename = "webrtc_signal")]
+    WebRTCSignal {
+        from_user_id: String,
+        to_user_id: String,
+        signal: serde_json::Value,
+    },
+    // Chiamate: il server tiene lo stato e inoltra accept/reject/cancel/end
+    // (con call_id) a entrambi i partecipanti
+    #[serde(rename = "call_invite")]
+    CallInvite {
+        call_id: String,
+        from_user_id: String,
+        to_user_id: String,
+        call_type: CallType,
+    },
+    #[serde(rename = "call_ringing")]
+    CallRinging {
+        call_id: String,
+        to_user_id: String,
+        call_type: CallType,
+    },
+    #[serde(rename = "call_accept")]
+    CallAccept {
+        call_id: String,
+    },
+    #[serde(rename = "call_reject")]
+    CallReject {
+        call_id: String,
+    },
+    #[serde(rename = "call_cancel")]
+    CallCancel {
+        call_id: String,
+    },
+    #[serde(rename = "call_end")]
+    CallEnd {
+        call_id: String,
+        duration: Option<i32>,
+    },
+    #[serde(rename = "call_busy")]
+    CallBusy {
+        call_id: String,
+        to_user_id: String,
+    },
+    #[serde(rename = "call_timeout")]
+    CallTimeout {
+        call_id: String,
+    },
+    #[serde(rename = "call_unavailable")]
+    CallUnavailable {
+        call_id: String,
+        to_user_id: String,
+    },
+    #[serde(rename = "missed_call")]
+    MissedCall {
+        call_id: String,
+        from_user_id: String,
+        call_type: CallType,
+        started_at: DateTime<Utc>,
+    },
+    // Nuovo messaggio diretto: inviato al destinatario e agli altri dispositivi del mittente
+    #[serde(rename = "message_new")]
+    MessageNew {
+        message: DirectMessage,
+    },
+    // Fine del replay; reset = alcuni eventi non sono più disponibili
+    #[serde(rename = "resumed")]
+    Resumed {
+        #[ts(type = "number")]
+        last_seq: i64,
+        reset: bool,
+    },
+    // Il server si sta arrestando (deploy): il socket verrà chiuso con close code 1012,
+    // il client deve riconnettersi dopo reconnect_after_ms (con jitter per non arrivare tutti insieme)
+    #[serde(rename = "server_restarting")]
+    ServerRestarting {
+        reconnect_after_ms: u32,
+    },
+    // Inviato solo alla connessione che ha causato l'errore
+    #[serde(rename = "error")]
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    #[serde(rename = "pong")]
+    Pong,
+}
+
+impl ServerMessage {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Eventi salvati nell'outbox e numerati (`seq`): non devono andare persi se il
+    /// client è offline o si riconnette. Presenza, chiamate e segnali sono effimeri.
+    pub fn is_durable(&self) -> bool {
+        matches!(
+            self,
+            Self::FriendAdded { .. }
+                | Self::FriendRequest { .. }
+                | Self::FriendRequestAccepted { .. }
+                | Self::FriendRequestRejected { .. }
+                | Self::FriendRequestCancelled { .. }
+                | Self::FriendRemoved { .. }
+                | Self::MissedCall { .. }
+                | Self::MessageNew { .. }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_code(result: Result<ClientMessage, ServerMessage>) -> Option<ErrorCode> {
+        match result {
+            Err(ServerMessage::Error { code, .. }) => Some(code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_parse_client_messages() {
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"hello","protocol_version":2}"#).unwrap(),
+            ClientMessage::Hello { protocol_version: 2, capabilities: None }
+        );
+        assert_eq!(ClientMessage::parse(r#"{"type":"resume"}"#).unwrap(), ClientMessage::Resume { last_seq: None });
+
+        assert_eq!(error_code(ClientMessage::parse("not json")), Some(ErrorCode::MalformedMessage));
+        assert_eq!(error_code(ClientMessage::parse(r#"{"seq":1}"#)), Some(ErrorCode::MalformedMessage));
+        assert_eq!(error_code(ClientMessage::parse(r#"{"type":"ack"}"#)), Some(ErrorCode::MalformedMessage));
+        assert_eq!(error_code(ClientMessage::parse(r#"{"type":"friend_added"}"#)), Some(ErrorCode::UnsupportedMessage));
+    }
+
+    #[test]
+    fn test_client_types_match_variants() {
+        // Ogni type elencato deve essere una variante (al massimo mancano dei campi)
+        for kind in ClientMessage::TYPES {
+            let frame = serde_json::json!({ "type": kind }).to_string();
+            if let Err(ServerMessage::Error { message, .. }) = ClientMessage::parse(&frame) {
+                assert!(!message.contains("unknown variant"), "{}", message);
+            }
+        }
+    }
+}
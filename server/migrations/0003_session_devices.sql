@@ -0,0 +1,14 @@
+-- Dispositivo delle sessioni; last_used_at diventa last_seen_at
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS device_name VARCHAR(100);
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS user_agent TEXT;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS ip_address VARCHAR(45);
+
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'sessions' AND column_name = 'last_used_at'
+    ) THEN
+        ALTER TABLE sessions RENAME COLUMN last_used_at TO last_seen_at;
+    END IF;
+END $$;
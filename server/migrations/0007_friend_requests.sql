@@ -0,0 +1,4 @@
+-- Richieste di amicizia in attesa e accettazione automatica
+ALTER TABLE users ADD COLUMN IF NOT EXISTS friend_auto_accept BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX IF NOT EXISTS idx_friendships_pending ON friendships(friend_id) WHERE status = 'pending';
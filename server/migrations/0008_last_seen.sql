@@ -0,0 +1,2 @@
+-- Ultimo accesso: chiusura dell'ultimo WebSocket
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_seen_at TIMESTAMP WITH TIME ZONE;
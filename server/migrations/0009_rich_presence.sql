@@ -0,0 +1,5 @@
+-- Stato scelto dall'utente e stato personalizzato
+ALTER TABLE users ADD COLUMN IF NOT EXISTS presence VARCHAR(20) NOT NULL DEFAULT 'online';
+ALTER TABLE users ADD COLUMN IF NOT EXISTS custom_status_text VARCHAR(128);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS custom_status_emoji VARCHAR(32);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS custom_status_expires_at TIMESTAMP WITH TIME ZONE;
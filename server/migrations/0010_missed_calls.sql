@@ -0,0 +1,5 @@
+-- Chiamate perse viste dal chiamato e notifica missed_call già consegnata
+ALTER TABLE call_history ADD COLUMN IF NOT EXISTS seen_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE call_history ADD COLUMN IF NOT EXISTS missed_notified BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX IF NOT EXISTS idx_call_history_missed ON call_history(callee_id) WHERE status = 'missed' AND seen_at IS NULL;
@@ -0,0 +1,14 @@
+-- Eventi WebSocket durevoli con numero di sequenza e ack per sessione
+ALTER TABLE users ADD COLUMN IF NOT EXISTS outbox_seq BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS ws_acked_seq BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS ws_outbox (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    PRIMARY KEY (user_id, seq)
+);
+
+-- missed_call ora passa dall'outbox
+ALTER TABLE call_history DROP COLUMN IF EXISTS missed_notified;
@@ -0,0 +1,6 @@
+-- Payload tra istanze troppo grandi per NOTIFY
+CREATE UNLOGGED TABLE IF NOT EXISTS ws_pubsub (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
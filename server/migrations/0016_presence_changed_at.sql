@@ -0,0 +1,3 @@
+-- Istante dell'ultimo cambio di presenza salvato: le scritture online/offline più
+-- vecchie (arrivate fuori ordine da socket o nodi diversi) vengono ignorate
+ALTER TABLE users ADD COLUMN IF NOT EXISTS presence_changed_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,11 @@
+-- Utenti impegnati in una chiamata (squillo o attiva), condivisi tra i nodi: ogni
+-- chiamata vive sul nodo del chiamante, ma il controllo "occupato" vale per tutto il cluster
+CREATE TABLE IF NOT EXISTS call_participants (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    call_id UUID NOT NULL,
+    node_id UUID NOT NULL, -- nodo che gestisce la chiamata
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_call_participants_call ON call_participants(call_id);
+CREATE INDEX IF NOT EXISTS idx_call_participants_node ON call_participants(node_id);
@@ -1,14 +1,30 @@
 -- Database Schema per GameCall
 -- PostgreSQL
+--
+-- Schema completo di riferimento. Le modifiche vanno aggiunte come nuova migrazione in
+-- migrations/ (applicate all'avvio del server) e riportate qui.
 
 -- Users table
 CREATE TABLE users (
     id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
     username VARCHAR(50) UNIQUE NOT NULL,
     password_hash VARCHAR(255) NOT NULL,
+    email VARCHAR(255) UNIQUE, -- opzionale, salvata in minuscolo
+    email_verified BOOLEAN NOT NULL DEFAULT FALSE,
+    totp_secret VARCHAR(64), -- base32; impostato all'enroll, attivo solo con totp_enabled
+    totp_enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    totp_last_step BIGINT, -- ultimo passo TOTP accettato (anti-replay)
     friend_code VARCHAR(20) UNIQUE NOT NULL,
     avatar_url TEXT,
-    status VARCHAR(20) DEFAULT 'offline',
+    status VARCHAR(20) DEFAULT 'offline', -- online finché l'utente ha almeno un WebSocket aperto
+    last_seen_at TIMESTAMP WITH TIME ZONE, -- chiusura dell'ultimo WebSocket
+    presence_changed_at TIMESTAMP WITH TIME ZONE, -- ultimo cambio di status salvato (ordina le scritture)
+    friend_auto_accept BOOLEAN NOT NULL DEFAULT FALSE, -- accetta subito le richieste di amicizia
+    outbox_seq BIGINT NOT NULL DEFAULT 0, -- ultimo seq assegnato agli eventi WebSocket durevoli
+    presence VARCHAR(20) NOT NULL DEFAULT 'online', -- stato scelto: online, idle, dnd, invisible, in-call
+    custom_status_text VARCHAR(128),
+    custom_status_emoji VARCHAR(32),
+    custom_status_expires_at TIMESTAMP WITH TIME ZONE, -- NULL = non scade
     created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
     updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
 );
@@ -18,21 +34,30 @@ CREATE TABLE friendships (
     id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
     user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
     friend_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
-    status VARCHAR(20) DEFAULT 'pending', -- pending, accepted, blocked
+    status VARCHAR(20) DEFAULT 'pending', -- pending, accepted, blocked (riga del bloccante verso il bloccato)
     created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
     UNIQUE(user_id, friend_id)
 );
 
 -- Call history (opzionale per tracking chiamate)
 CREATE TABLE call_history (
-    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(), -- call_id dei messaggi WebSocket
     caller_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
     callee_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
-    call_type VARCHAR(20) NOT NULL, -- video, audio
-    duration INTEGER, -- secondi
-    status VARCHAR(20) NOT NULL, -- completed, missed, rejected
-    started_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
-    ended_at TIMESTAMP WITH TIME ZONE
+    call_type VARCHAR(20) NOT NULL, -- video, audio, screen
+    duration INTEGER, -- secondi di conversazione (0 se nessuno ha risposto)
+    status VARCHAR(20) NOT NULL, -- completed, missed, rejected, cancelled, busy
+    started_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(), -- invio dell'invito
+    ended_at TIMESTAMP WITH TIME ZONE,
+    seen_at TIMESTAMP WITH TIME ZONE -- chiamata persa vista dal chiamato
+);
+
+-- Utenti in una chiamata (squillo o attiva): il controllo "occupato" vale per tutti i nodi
+CREATE TABLE call_participants (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    call_id UUID NOT NULL,
+    node_id UUID NOT NULL, -- nodo che gestisce la chiamata
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
 );
 
 -- Indexes per performance
@@ -40,5 +65,109 @@ CREATE INDEX idx_users_friend_code ON users(friend_code);
 CREATE INDEX idx_users_username ON users(username);
 CREATE INDEX idx_friendships_user_id ON friendships(user_id);
 CREATE INDEX idx_friendships_friend_id ON friendships(friend_id);
+CREATE INDEX idx_friendships_pending ON friendships(friend_id) WHERE status = 'pending';
 CREATE INDEX idx_call_history_caller ON call_history(caller_id);
 CREATE INDEX idx_call_history_callee ON call_history(callee_id);
+CREATE INDEX idx_call_history_missed ON call_history(callee_id) WHERE status = 'missed' AND seen_at IS NULL;
+CREATE INDEX idx_call_participants_call ON call_participants(call_id);
+CREATE INDEX idx_call_participants_node ON call_participants(node_id);
+
+-- Messaggi diretti tra amici
+CREATE TABLE messages (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    sender_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    recipient_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    content TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    read_at TIMESTAMP WITH TIME ZONE -- NULL finché il destinatario non apre la conversazione
+);
+
+CREATE INDEX idx_messages_conversation ON messages(sender_id, recipient_id, created_at DESC, id DESC);
+CREATE INDEX idx_messages_recipient ON messages(recipient_id, created_at DESC);
+CREATE INDEX idx_messages_unread ON messages(recipient_id, sender_id) WHERE read_at IS NULL;
+
+-- Sessions (una riga per login, con refresh token a rotazione)
+CREATE TABLE sessions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    refresh_token_hash VARCHAR(64) NOT NULL, -- sha256 hex del refresh token corrente
+    device_name VARCHAR(100),
+    user_agent TEXT,
+    ip_address VARCHAR(45),
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    last_seen_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE,
+    ws_acked_seq BIGINT NOT NULL DEFAULT 0 -- ultimo evento WebSocket confermato da questa sessione
+);
+
+CREATE INDEX idx_sessions_user_id ON sessions(user_id);
+
+-- Eventi WebSocket durevoli (amicizie, chiamate perse) da rinviare dopo una riconnessione
+CREATE TABLE ws_outbox (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL, -- progressivo per utente (users.outbox_seq)
+    payload TEXT NOT NULL, -- JSON del messaggio, senza seq
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    PRIMARY KEY (user_id, seq)
+);
+
+-- Messaggi tra istanze troppo grandi per NOTIFY (es. segnali WebRTC): il NOTIFY porta solo l'id
+CREATE UNLOGGED TABLE ws_pubsub (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+-- Ticket monouso per aprire il WebSocket (evita il JWT nella query string)
+CREATE TABLE ws_tickets (
+    ticket_hash VARCHAR(64) PRIMARY KEY, -- sha256 hex del ticket
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    session_id UUID NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Token monouso per il reset della password
+CREATE TABLE password_resets (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash VARCHAR(64) UNIQUE NOT NULL, -- sha256 hex del token inviato via email
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_password_resets_user_id ON password_resets(user_id);
+
+-- Token per la verifica dell'indirizzo email
+CREATE TABLE email_verifications (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    email VARCHAR(255) NOT NULL, -- indirizzo a cui è stato inviato il token
+    token_hash VARCHAR(64) UNIQUE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_email_verifications_user_id ON email_verifications(user_id);
+
+-- Challenge 2FA del login (una per mfa_token): eliminata dopo il login o troppi codici errati
+CREATE TABLE mfa_challenges (
+    id UUID PRIMARY KEY, -- jti del mfa_token
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    attempts INTEGER NOT NULL DEFAULT 0, -- codici provati
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Codici di recupero 2FA monouso (salvati solo come hash)
+CREATE TABLE recovery_codes (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR(255) NOT NULL, -- hash argon2 (formato PHC, con salt)
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_recovery_codes_user_id ON recovery_codes(user_id);
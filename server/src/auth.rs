@@ -1,45 +1,82 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
     Json,
 };
 use serde::{Deserialize, Serialize};
-use jsonwebtoken::{encode, Header, EncodingKey};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
+use std::net::SocketAddr;
 use std::sync::Arc;
 use chrono::{Utc, Duration};
 use uuid::Uuid;
 
-use crate::{AppState, models::User, utils::generate_friend_code};
+use crate::{AppState, error::{AppError, AppJson, AppResult}, jwt::JwtKeys, mfa, models::User, sessions::{self, DeviceInfo, RotateOutcome}, utils::generate_friend_code};
+
+/// Durata dell'access token JWT (i refresh token durano molto di più)
+const ACCESS_TOKEN_TTL_MINUTES: i64 = 15;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // user_id
     pub username: String,
+    pub sid: String, // session_id
     pub exp: i64,
 }
 
+impl Claims {
+    pub fn user_id(&self) -> AppResult<Uuid> {
+        Uuid::parse_str(&self.sub).map_err(|_| AppError::InvalidToken)
+    }
+
+    pub fn session_id(&self) -> AppResult<Uuid> {
+        Uuid::parse_str(&self.sid).map_err(|_| AppError::InvalidToken)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct RegisterRequest {
     pub username: String,
     pub password: String,
+    pub device_name: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
+    pub device_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
 }
 
 #[derive(Debug, Serialize)]
 pub struct AuthResponse {
     pub token: String,
+    pub refresh_token: String,
     pub user: UserResponse,
 }
 
+/// Risposta al login quando l'account ha la 2FA attiva: il client deve
+/// completare il login con /auth/login/mfa entro pochi minuti
+#[derive(Debug, Serialize)]
+pub struct MfaChallengeResponse {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Authenticated(AuthResponse),
+    MfaRequired(MfaChallengeResponse),
+}
+
 #[derive(Debug, Serialize)]
 pub struct UserResponse {
     pub id: String,
@@ -47,6 +84,10 @@ pub struct UserResponse {
     pub friend_code: String,
     pub avatar_url: Option<String>,
     pub status: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub mfa_enabled: bool,
+    pub friend_auto_accept: bool,
 }
 
 impl From<User> for UserResponse {
@@ -54,6 +95,10 @@ impl From<User> for UserResponse {
         Self {
             id: user.id.to_string(),
             username: user.username,
+            email: user.email,
+            email_verified: user.email_verified,
+            mfa_enabled: user.totp_enabled,
+            friend_auto_accept: user.friend_auto_accept,
             friend_code: user.friend_code,
             avatar_url: user.avatar_url,
             status: user.status,
@@ -63,17 +108,17 @@ impl From<User> for UserResponse {
 
 pub async fn register(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<RegisterRequest>,
-) -> Result<Json<AuthResponse>, (StatusCode, String)> {
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    AppJson(payload): AppJson<RegisterRequest>,
+) -> AppResult<Json<AuthResponse>> {
     // Validazione username
     if payload.username.len() < 3 || payload.username.len() > 50 {
-        return Err((StatusCode::BAD_REQUEST, "Username must be between 3 and 50 characters".to_string()));
+        return Err(AppError::Validation("Username must be between 3 and 50 characters".to_string()));
     }
 
     // Validazione password
-    if payload.password.len() < 6 {
-        return Err((StatusCode::BAD_REQUEST, "Password must be at least 6 characters".to_string()));
-    }
+    validate_password(&payload.password)?;
 
     // Controlla se username esiste già
     let existing_user = sqlx::query_as::<_, User>(
@@ -81,20 +126,14 @@ pub async fn register(
     )
     .bind(&payload.username)
     .fetch_optional(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .await?;
 
     if existing_user.is_some() {
-        return Err((StatusCode::CONFLICT, "Username already exists".to_string()));
+        return Err(AppError::UsernameTaken);
     }
 
     // Hash password
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(payload.password.as_bytes(), &salt)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-        .to_string();
+    let password_hash = hash_password(&payload.password)?;
 
     // Genera friend code univoco
     let mut friend_code = generate_friend_code();
@@ -106,8 +145,7 @@ pub async fn register(
         )
         .bind(&friend_code)
         .fetch_optional(&state.db)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        .await?;
 
         if existing.is_none() {
             break;
@@ -130,63 +168,53 @@ pub async fn register(
     .bind(&password_hash)
     .bind(&friend_code)
     .fetch_one(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .await?;
 
-    // Genera JWT
-    let token = create_jwt(&state.jwt_secret, &user)?;
-
-    Ok(Json(AuthResponse {
-        token,
-        user: user.into(),
-    }))
+    let device = DeviceInfo::from_request(&headers, addr, state.trust_proxy_headers, payload.device_name);
+    Ok(Json(complete_login(&state, user, device).await?))
 }
 
 pub async fn login(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<LoginRequest>,
-) -> Result<Json<AuthResponse>, (StatusCode, String)> {
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    AppJson(payload): AppJson<LoginRequest>,
+) -> AppResult<Json<LoginResponse>> {
     // Trova utente
     let user = sqlx::query_as::<_, User>(
         "SELECT * FROM users WHERE username = $1"
     )
     .bind(&payload.username)
     .fetch_optional(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-    .ok_or((StatusCode::UNAUTHORIZED, "Invalid credentials".to_string()))?;
+    .await?
+    .ok_or(AppError::InvalidCredentials)?;
 
     // Verifica password
-    let parsed_hash = PasswordHash::new(&user.password_hash)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-
-    Argon2::default()
-        .verify_password(payload.password.as_bytes(), &parsed_hash)
-        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid credentials".to_string()))?;
+    if !verify_password(&payload.password, &user.password_hash)? {
+        return Err(AppError::InvalidCredentials);
+    }
 
-    // Aggiorna status a online
-    sqlx::query("UPDATE users SET status = 'online' WHERE id = $1")
-        .bind(user.id)
-        .execute(&state.db)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    // Con la 2FA attiva serve un secondo passaggio prima di creare la sessione
+    if user.totp_enabled {
+        let mfa_token = mfa::create_mfa_token(&state, &user, payload.device_name).await?;
+        return Ok(Json(LoginResponse::MfaRequired(MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token,
+        })));
+    }
 
-    // Genera JWT
-    let token = create_jwt(&state.jwt_secret, &user)?;
+    let device = DeviceInfo::from_request(&headers, addr, state.trust_proxy_headers, payload.device_name);
+    let response = complete_login(&state, user, device).await?;
 
-    Ok(Json(AuthResponse {
-        token,
-        user: user.into(),
-    }))
+    Ok(Json(LoginResponse::Authenticated(response)))
 }
 
 pub async fn me(
     State(state): State<Arc<AppState>>,
     axum::Extension(claims): axum::Extension<Claims>,
-) -> Result<Json<UserResponse>, (StatusCode, String)> {
+) -> AppResult<Json<UserResponse>> {
     // Estrai user_id dal token JWT
-    let user_id = Uuid::parse_str(&claims.sub)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid user ID".to_string()))?;
+    let user_id = claims.user_id()?;
 
     // Recupera utente dal database
     let user = sqlx::query_as::<_, User>(
@@ -194,29 +222,113 @@ pub async fn me(
     )
     .bind(user_id)
     .fetch_optional(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-    .ok_or((StatusCode::NOT_FOUND, "User not found".to_string()))?;
+    .await?
+    .ok_or(AppError::UserNotFound)?;
 
     Ok(Json(user.into()))
 }
 
-fn create_jwt(secret: &str, user: &User) -> Result<String, (StatusCode, String)> {
+pub async fn refresh(
+    State(state): State<Arc<AppState>>,
+    AppJson(payload): AppJson<RefreshRequest>,
+) -> AppResult<Json<AuthResponse>> {
+    let outcome = sessions::rotate_refresh_token(&state.db, &payload.refresh_token)
+        .await?;
+
+    let (session_id, user_id, refresh_token) = match outcome {
+        RotateOutcome::Rotated { session_id, user_id, refresh_token } => (session_id, user_id, refresh_token),
+        RotateOutcome::Reused { session_id } => {
+            // Anche i socket già aperti con la sessione (es. da chi ha rubato il token)
+            state.ws_state.close_session(&session_id.to_string()).await;
+            return Err(AppError::RefreshTokenReused);
+        }
+        RotateOutcome::Invalid => {
+            return Err(AppError::InvalidRefreshToken);
+        }
+    };
+
+    let user = sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_one(&state.db)
+    .await?;
+
+    let token = create_jwt(&state.jwt_keys, &user, &session_id.to_string())?;
+
+    Ok(Json(AuthResponse {
+        token,
+        refresh_token,
+        user: user.into(),
+    }))
+}
+
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
+    let session_id = claims.session_id()?;
+
+    sessions::revoke_session(&state.db, user_id, session_id)
+        .await?;
+
+    state.ws_state.close_session(&claims.sid).await;
+
+    Ok(StatusCode::OK)
+}
+
+/// Crea una nuova sessione per l'utente (dopo password ed eventuale 2FA) e
+/// restituisce access + refresh token. Lo status online lo gestisce il WebSocket.
+pub(crate) async fn complete_login(state: &AppState, user: User, device: DeviceInfo) -> AppResult<AuthResponse> {
+    let (session_id, refresh_token) = sessions::create_session(&state.db, user.id, device)
+        .await?;
+
+    let token = create_jwt(&state.jwt_keys, &user, &session_id.to_string())?;
+
+    Ok(AuthResponse {
+        token,
+        refresh_token,
+        user: user.into(),
+    })
+}
+
+pub(crate) fn validate_password(password: &str) -> AppResult<()> {
+    if password.len() < 6 {
+        return Err(AppError::Validation("Password must be at least 6 characters".to_string()));
+    }
+    Ok(())
+}
+
+pub(crate) fn hash_password(password: &str) -> AppResult<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(AppError::internal)
+}
+
+pub(crate) fn verify_password(password: &str, password_hash: &str) -> AppResult<bool> {
+    let parsed_hash = PasswordHash::new(password_hash).map_err(AppError::internal)?;
+
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_ok())
+}
+
+fn create_jwt(keys: &JwtKeys, user: &User, session_id: &str) -> AppResult<String> {
     let expiration = Utc::now()
-        .checked_add_signed(Duration::days(7))
+        .checked_add_signed(Duration::minutes(ACCESS_TOKEN_TTL_MINUTES))
         .expect("valid timestamp")
         .timestamp();
 
     let claims = Claims {
         sub: user.id.to_string(),
         username: user.username.clone(),
+        sid: session_id.to_string(),
         exp: expiration,
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
-    )
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    keys.encode(&claims)
+        .map_err(AppError::internal)
 }
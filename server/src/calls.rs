@@ -0,0 +1,716 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    Extension,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use gamecall_protocol::{CallType, ClientMessage, ErrorCode, ServerMessage};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::Claims,
+    error::{AppQuery, AppResult},
+    websocket::WsState,
+};
+
+/// Dopo quanto tempo una chiamata senza risposta diventa persa
+const RING_TIMEOUT: Duration = Duration::from_secs(60);
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+/// Esito salvato in call_history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CallOutcome {
+    Completed,
+    Missed,
+    Rejected,
+    Cancelled,
+    Busy,
+}
+
+impl CallOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Completed => "completed",
+            Self::Missed => "missed",
+            Self::Rejected => "rejected",
+            Self::Cancelled => "cancelled",
+            Self::Busy => "busy",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub id: Uuid,
+    pub caller_id: String,
+    pub callee_id: String,
+    pub call_type: CallType,
+    pub started_at: DateTime<Utc>,
+    // None finché il chiamato non risponde
+    pub answered_at: Option<DateTime<Utc>>,
+}
+
+impl Call {
+    fn new(caller_id: &str, callee_id: &str, call_type: CallType, now: DateTime<Utc>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            caller_id: caller_id.to_string(),
+            callee_id: callee_id.to_string(),
+            call_type,
+            started_at: now,
+            answered_at: None,
+        }
+    }
+
+    fn involves(&self, user_id: &str) -> bool {
+        self.caller_id == user_id || self.callee_id == user_id
+    }
+
+    /// Secondi di conversazione (0 se nessuno ha risposto)
+    fn duration(&self, ended_at: DateTime<Utc>) -> i32 {
+        self.answered_at
+            .map_or(0, |answered_at| (ended_at - answered_at).num_seconds().max(0) as i32)
+    }
+}
+
+/// Azione del client che chiude una chiamata
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hangup {
+    Reject,
+    Cancel,
+    End,
+}
+
+#[derive(Default)]
+struct Calls {
+    by_id: HashMap<Uuid, Call>,
+    // Chiamata in corso (squillo o attiva) per ogni partecipante
+    by_user: HashMap<String, Uuid>,
+}
+
+impl Calls {
+    fn remove(&mut self, call_id: Uuid) -> Option<Call> {
+        let call = self.by_id.remove(&call_id)?;
+        self.by_user.remove(&call.caller_id);
+        self.by_user.remove(&call.callee_id);
+        Some(call)
+    }
+}
+
+#[derive(Debug)]
+pub enum InviteResult {
+    Ringing(Call),
+    // Il chiamato appare offline a chi chiama (anche se è invisibile): la chiamata è
+    // subito persa, senza rivelare se è occupato
+    Unavailable(Call),
+    // Il chiamato è già in un'altra chiamata: la chiamata viene registrata come busy
+    Busy(Call),
+    // Chi chiama ha già una chiamata in corso
+    CallerBusy,
+}
+
+/// Stato delle chiamate in corso; il server è l'unica fonte di verità
+#[derive(Clone, Default)]
+pub struct CallRegistry {
+    inner: Arc<Mutex<Calls>>,
+}
+
+impl CallRegistry {
+    pub async fn contains(&self, call_id: Uuid) -> bool {
+        self.inner.lock().await.by_id.contains_key(&call_id)
+    }
+
+    /// `available`: il chiamato appare online a chi chiama
+    pub async fn invite(
+        &self,
+        caller_id: &str,
+        callee_id: &str,
+        call_type: CallType,
+        available: bool,
+        now: DateTime<Utc>,
+    ) -> InviteResult {
+        let mut calls = self.inner.lock().await;
+
+        if calls.by_user.contains_key(caller_id) {
+            return InviteResult::CallerBusy;
+        }
+
+        let call = Call::new(caller_id, callee_id, call_type, now);
+        if !available {
+            return InviteResult::Unavailable(call);
+        }
+        if calls.by_user.contains_key(callee_id) {
+            return InviteResult::Busy(call);
+        }
+
+        calls.by_user.insert(caller_id.to_string(), call.id);
+        calls.by_user.insert(callee_id.to_string(), call.id);
+        calls.by_id.insert(call.id, call.clone());
+        InviteResult::Ringing(call)
+    }
+
+    /// Solo il chiamato può rispondere, e solo mentre squilla
+    pub async fn accept(&self, call_id: Uuid, user_id: &str, now: DateTime<Utc>) -> Option<Call> {
+        let mut calls = self.inner.lock().await;
+        let call = calls.by_id.get_mut(&call_id)?;
+
+        if call.callee_id != user_id || call.answered_at.is_some() {
+            return None;
+        }
+
+        call.answered_at = Some(now);
+        Some(call.clone())
+    }
+
+    /// Chiude la chiamata se l'azione è lecita per chi la richiede nello stato attuale
+    pub async fn hang_up(&self, call_id: Uuid, user_id: &str, action: Hangup) -> Option<(Call, CallOutcome)> {
+        let mut calls = self.inner.lock().await;
+        let call = calls.by_id.get(&call_id)?;
+
+        let is_caller = call.caller_id == user_id;
+        let ringing = call.answered_at.is_none();
+        let outcome = match action {
+            Hangup::Reject if ringing && call.callee_id == user_id => CallOutcome::Rejected,
+            Hangup::Cancel if ringing && is_caller => CallOutcome::Cancelled,
+            Hangup::End if !ringing && call.involves(user_id) => CallOutcome::Completed,
+            // call_end durante lo squillo equivale ad annullare/rifiutare
+            Hangup::End if ringing && is_caller => CallOutcome::Cancelled,
+            Hangup::End if ringing && call.callee_id == user_id => CallOutcome::Rejected,
+            _ => return None,
+        };
+
+        calls.remove(call_id).map(|call| (call, outcome))
+    }
+
+    /// Scadenza dello squillo: la chiamata diventa persa se nessuno ha risposto
+    pub async fn expire(&self, call_id: Uuid) -> Option<Call> {
+        let mut calls = self.inner.lock().await;
+        if calls.by_id.get(&call_id)?.answered_at.is_some() {
+            return None;
+        }
+        calls.remove(call_id)
+    }
+
+    /// L'utente si è disconnesso da tutti i dispositivi: chiude la sua chiamata
+    pub async fn drop_user(&self, user_id: &str) -> Option<(Call, CallOutcome)> {
+        let mut calls = self.inner.lock().await;
+        let call_id = *calls.by_user.get(user_id)?;
+        let call = calls.remove(call_id)?;
+
+        let outcome = match (call.answered_at, call.caller_id == user_id) {
+            (Some(_), _) => CallOutcome::Completed,
+            (None, true) => CallOutcome::Cancelled,
+            (None, false) => CallOutcome::Missed,
+        };
+        Some((call, outcome))
+    }
+}
+
+/// Filtri di GET /calls (tutti opzionali)
+#[derive(Debug, Deserialize)]
+pub struct CallHistoryQuery {
+    pub contact_id: Option<Uuid>,
+    pub call_type: Option<CallType>,
+    pub status: Option<CallOutcome>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, FromRow)]
+struct CallHistoryRow {
+    id: Uuid,
+    caller_id: Uuid,
+    contact_id: Uuid,
+    contact_username: String,
+    contact_avatar_url: Option<String>,
+    call_type: String,
+    status: String,
+    duration: Option<i32>,
+    started_at: DateTime<Utc>,
+    ended_at: Option<DateTime<Utc>>,
+    seen_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CallHistoryResponse {
+    pub id: String,
+    pub contact_id: String,
+    pub contact_username: String,
+    pub contact_avatar_url: Option<String>,
+    pub direction: &'static str, // incoming, outgoing
+    pub call_type: String,
+    pub status: String,
+    pub duration: Option<i32>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub seen: bool, // false solo per le chiamate perse in arrivo non ancora viste
+}
+
+#[derive(Debug, Serialize)]
+pub struct MissedCountResponse {
+    pub count: i64,
+}
+
+pub async fn list_calls(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    AppQuery(query): AppQuery<CallHistoryQuery>,
+) -> AppResult<Json<Vec<CallHistoryResponse>>> {
+    let user_id = claims.user_id()?;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    // Il "contatto" è l'altro partecipante, qualunque sia la direzione
+    let rows = sqlx::query_as::<_, CallHistoryRow>(
+        r#"
+        SELECT c.id, c.caller_id, u.id AS contact_id, u.username AS contact_username,
+               u.avatar_url AS contact_avatar_url, c.call_type, c.status, c.duration,
+               c.started_at, c.ended_at, c.seen_at
+        FROM call_history c
+        JOIN users u ON u.id = CASE WHEN c.caller_id = $1 THEN c.callee_id ELSE c.caller_id END
+        WHERE (c.caller_id = $1 OR c.callee_id = $1)
+          AND ($2::uuid IS NULL OR u.id = $2)
+          AND ($3::varchar IS NULL OR c.call_type = $3)
+          AND ($4::varchar IS NULL OR c.status = $4)
+        ORDER BY c.started_at DESC, c.id
+        LIMIT $5 OFFSET $6
+        "#
+    )
+    .bind(user_id)
+    .bind(query.contact_id)
+    .bind(query.call_type.map(CallType::as_str))
+    .bind(query.status.map(CallOutcome::as_str))
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&state.db)
+    .await?;
+
+    let response = rows
+        .into_iter()
+        .map(|row| {
+            let incoming = row.caller_id != user_id;
+            CallHistoryResponse {
+                id: row.id.to_string(),
+                contact_id: row.contact_id.to_string(),
+                contact_username: row.contact_username,
+                contact_avatar_url: row.contact_avatar_url,
+                direction: if incoming { "incoming" } else { "outgoing" },
+                seen: !(incoming && row.status == CallOutcome::Missed.as_str() && row.seen_at.is_none()),
+                call_type: row.call_type,
+                status: row.status,
+                duration: row.duration,
+                started_at: row.started_at,
+                ended_at: row.ended_at,
+            }
+        })
+        .collect();
+
+    Ok(Json(response))
+}
+
+/// Chiamate perse in arrivo non ancora viste (badge)
+pub async fn missed_count(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> AppResult<Json<MissedCountResponse>> {
+    let user_id = claims.user_id()?;
+
+    let count: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM call_history WHERE callee_id = $1 AND status = 'missed' AND seen_at IS NULL"
+    )
+    .bind(user_id)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(MissedCountResponse { count }))
+}
+
+pub async fn mark_missed_seen(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
+
+    sqlx::query(
+        r#"
+        UPDATE call_history
+        SET seen_at = NOW()
+        WHERE callee_id = $1 AND status = 'missed' AND seen_at IS NULL
+        "#
+    )
+    .bind(user_id)
+    .execute(&state.db)
+    .await?;
+
+    Ok(StatusCode::OK)
+}
+
+/// Gestisce i messaggi call_* inviati da un client. Una chiamata vive sul nodo a cui
+/// è connesso il chiamante: se è su un altro nodo il messaggio viene inoltrato.
+/// Un `call_invite` rifiutato restituisce l'errore per la connessione che l'ha inviato.
+pub async fn handle_message(ws_state: &WsState, user_id: &str, message: ClientMessage) -> Result<(), ServerMessage> {
+    if let ClientMessage::CallInvite { to_user_id, call_type } = message {
+        return invite(ws_state, user_id, &to_user_id, call_type).await;
+    }
+
+    if let Some(call_id) = call_id_of(&message) {
+        if !ws_state.calls().contains(call_id).await {
+            ws_state.forward_call_message(user_id, message).await;
+            return Ok(());
+        }
+    }
+
+    process(ws_state, user_id, message).await;
+    Ok(())
+}
+
+/// Messaggio inoltrato da un altro nodo: lo gestisce solo il nodo che ha la chiamata
+pub async fn handle_forwarded(ws_state: &WsState, user_id: &str, message: ClientMessage) {
+    let Some(call_id) = call_id_of(&message) else { return };
+    if ws_state.calls().contains(call_id).await {
+        process(ws_state, user_id, message).await;
+    }
+}
+
+fn call_id_of(message: &ClientMessage) -> Option<Uuid> {
+    match message {
+        ClientMessage::CallAccept { call_id }
+        | ClientMessage::CallReject { call_id }
+        | ClientMessage::CallCancel { call_id }
+        | ClientMessage::CallEnd { call_id } => Uuid::parse_str(call_id).ok(),
+        _ => None,
+    }
+}
+
+async fn process(ws_state: &WsState, user_id: &str, message: ClientMessage) {
+    match message {
+        ClientMessage::CallAccept { call_id } => {
+            let Ok(id) = Uuid::parse_str(&call_id) else { return };
+            match ws_state.calls().accept(id, user_id, Utc::now()).await {
+                Some(call) => {
+                    tracing::info!("✅ [Call] Chiamata {} accettata", call.id);
+                    // Anche agli altri dispositivi del chiamato, che smettono di squillare
+                    let message = ServerMessage::CallAccept { call_id };
+                    ws_state.send_to_user(&call.caller_id, &message).await;
+                    ws_state.send_to_user(&call.callee_id, &message).await;
+                }
+                None => tracing::info!("🚫 [Call] call_accept non valido da {} per {}", user_id, call_id),
+            }
+        }
+        ClientMessage::CallReject { call_id } => hang_up(ws_state, user_id, call_id, Hangup::Reject).await,
+        ClientMessage::CallCancel { call_id } => hang_up(ws_state, user_id, call_id, Hangup::Cancel).await,
+        ClientMessage::CallEnd { call_id } => hang_up(ws_state, user_id, call_id, Hangup::End).await,
+        _ => {}
+    }
+}
+
+/// Chiude l'eventuale chiamata di un utente che si è disconnesso
+pub async fn drop_user(ws_state: &WsState, user_id: &str) {
+    let Some((call, outcome)) = ws_state.calls().drop_user(user_id).await else { return };
+    tracing::info!("📴 [Call] Chiamata {} chiusa: {} disconnesso", call.id, user_id);
+    finish(ws_state, &call, outcome).await;
+}
+
+async fn invite(ws_state: &WsState, caller_id: &str, callee_id: &str, call_type: CallType) -> Result<(), ServerMessage> {
+    // Si possono chiamare solo gli amici accettati
+    if caller_id == callee_id {
+        return Err(ServerMessage::error(ErrorCode::NotFriends, "You cannot call yourself"));
+    }
+    if !ws_state.friends_of(caller_id).await.contains(callee_id) {
+        tracing::info!("🚫 [Call] Chiamata da {} a {} non consentita", caller_id, callee_id);
+        return Err(ServerMessage::error(ErrorCode::NotFriends, "Calls can only be made to accepted friends"));
+    }
+
+    // Conta la presenza vista dagli amici: un utente invisibile non può essere chiamato
+    let available = ws_state.visible_presence(callee_id).await.status != "offline";
+
+    let call = match ws_state.calls().invite(caller_id, callee_id, call_type, available, Utc::now()).await {
+        InviteResult::Ringing(call) => call,
+        InviteResult::Unavailable(call) => {
+            tracing::info!("📵 [Call] {} non disponibile, chiamata {} persa", callee_id, call.id);
+            ws_state
+                .send_to_user(
+                    caller_id,
+                    &ServerMessage::CallUnavailable {
+                        call_id: call.id.to_string(),
+                        to_user_id: callee_id.to_string(),
+                    },
+                )
+                .await;
+            notify_missed(ws_state, &call).await;
+            record(ws_state.db(), &call, CallOutcome::Missed, Utc::now()).await;
+            return Ok(());
+        }
+        InviteResult::Busy(call) => {
+            reject_busy(ws_state, &call).await;
+            return Ok(());
+        }
+        InviteResult::CallerBusy => return Err(caller_busy(caller_id)),
+    };
+
+    // Il registro conosce solo le chiamate di questo nodo: quelle degli altri nodi
+    // risultano dalla tabella condivisa
+    match claim(ws_state.db(), &call, ws_state.node_id()).await {
+        Ok(busy) if busy.iter().any(|id| id == caller_id) => {
+            ws_state.calls().expire(call.id).await;
+            return Err(caller_busy(caller_id));
+        }
+        Ok(busy) if !busy.is_empty() => {
+            ws_state.calls().expire(call.id).await;
+            reject_busy(ws_state, &call).await;
+            return Ok(());
+        }
+        Ok(_) => {}
+        Err(e) => tracing::error!("❌ [Call] Registrazione partecipanti di {} fallita: {}", call.id, e),
+    }
+
+    tracing::info!("📞 [Call] {} chiama {} ({})", caller_id, callee_id, call.id);
+    let call_id = call.id.to_string();
+    ws_state
+        .send_to_user(
+            callee_id,
+            &ServerMessage::CallInvite {
+                call_id: call_id.clone(),
+                from_user_id: caller_id.to_string(),
+                to_user_id: callee_id.to_string(),
+                call_type,
+            },
+        )
+        .await;
+    ws_state
+        .send_to_user(
+            caller_id,
+            &ServerMessage::CallRinging {
+                call_id,
+                to_user_id: callee_id.to_string(),
+                call_type,
+            },
+        )
+        .await;
+
+    schedule_ring_timeout(ws_state.clone(), call.id);
+    Ok(())
+}
+
+fn caller_busy(caller_id: &str) -> ServerMessage {
+    tracing::info!("🚫 [Call] {} ha già una chiamata in corso", caller_id);
+    ServerMessage::error(ErrorCode::CallerBusy, "You are already in a call")
+}
+
+// Il chiamato è già in un'altra chiamata: la chiamata viene registrata come busy
+async fn reject_busy(ws_state: &WsState, call: &Call) {
+    tracing::info!("📵 [Call] {} occupato, chiamata {} rifiutata", call.callee_id, call.id);
+    ws_state
+        .send_to_user(
+            &call.caller_id,
+            &ServerMessage::CallBusy {
+                call_id: call.id.to_string(),
+                to_user_id: call.callee_id.clone(),
+            },
+        )
+        .await;
+    record(ws_state.db(), call, CallOutcome::Busy, Utc::now()).await;
+}
+
+async fn hang_up(ws_state: &WsState, user_id: &str, call_id: String, action: Hangup) {
+    let Ok(id) = Uuid::parse_str(&call_id) else { return };
+    match ws_state.calls().hang_up(id, user_id, action).await {
+        Some((call, outcome)) => finish(ws_state, &call, outcome).await,
+        None => tracing::info!("🚫 [Call] {:?} non valido da {} per {}", action, user_id, call_id),
+    }
+}
+
+/// Avvisa entrambi i partecipanti (tutti i dispositivi) e salva la chiamata
+async fn finish(ws_state: &WsState, call: &Call, outcome: CallOutcome) {
+    let ended_at = Utc::now();
+    let call_id = call.id.to_string();
+    let message = match outcome {
+        CallOutcome::Rejected => ServerMessage::CallReject { call_id },
+        CallOutcome::Cancelled => ServerMessage::CallCancel { call_id },
+        CallOutcome::Missed => ServerMessage::CallTimeout { call_id },
+        _ => ServerMessage::CallEnd {
+            call_id,
+            duration: Some(call.duration(ended_at)),
+        },
+    };
+
+    ws_state.send_to_user(&call.caller_id, &message).await;
+    ws_state.send_to_user(&call.callee_id, &message).await;
+    release(ws_state.db(), call.id).await;
+
+    if outcome == CallOutcome::Missed {
+        notify_missed(ws_state, call).await;
+    }
+
+    record(ws_state.db(), call, outcome, ended_at).await;
+}
+
+/// missed_call è durevole: se il chiamato è offline lo riceve al resume
+async fn notify_missed(ws_state: &WsState, call: &Call) {
+    ws_state
+        .send_to_user(
+            &call.callee_id,
+            &ServerMessage::MissedCall {
+                call_id: call.id.to_string(),
+                from_user_id: call.caller_id.clone(),
+                call_type: call.call_type,
+                started_at: call.started_at,
+            },
+        )
+        .await;
+}
+
+fn schedule_ring_timeout(ws_state: WsState, call_id: Uuid) {
+    tokio::spawn(async move {
+        tokio::time::sleep(RING_TIMEOUT).await;
+
+        if let Some(call) = ws_state.calls().expire(call_id).await {
+            tracing::info!("⏰ [Call] Chiamata {} senza risposta", call_id);
+            finish(&ws_state, &call, CallOutcome::Missed).await;
+        }
+    });
+}
+
+/// Occupa chiamante e chiamato in `call_participants` (condivisa tra i nodi) con un solo
+/// INSERT; restituisce quelli già in un'altra chiamata, e in quel caso non occupa nessuno
+async fn claim(db: &PgPool, call: &Call, node_id: Uuid) -> Result<Vec<String>, sqlx::Error> {
+    let (Ok(caller_id), Ok(callee_id)) = (Uuid::parse_str(&call.caller_id), Uuid::parse_str(&call.callee_id)) else {
+        return Ok(Vec::new());
+    };
+
+    let claimed: Vec<Uuid> = sqlx::query_scalar(
+        r#"
+        INSERT INTO call_participants (user_id, call_id, node_id)
+        VALUES ($1, $3, $4), ($2, $3, $4)
+        ON CONFLICT (user_id) DO NOTHING
+        RETURNING user_id
+        "#
+    )
+    .bind(caller_id)
+    .bind(callee_id)
+    .bind(call.id)
+    .bind(node_id)
+    .fetch_all(db)
+    .await?;
+
+    let busy: Vec<String> = [caller_id, callee_id]
+        .into_iter()
+        .filter(|id| !claimed.contains(id))
+        .map(|id| id.to_string())
+        .collect();
+    if !busy.is_empty() {
+        release(db, call.id).await;
+    }
+
+    Ok(busy)
+}
+
+/// Libera i partecipanti di una chiamata finita
+async fn release(db: &PgPool, call_id: Uuid) {
+    let result = sqlx::query("DELETE FROM call_participants WHERE call_id = $1")
+        .bind(call_id)
+        .execute(db)
+        .await;
+
+    if let Err(e) = result {
+        tracing::error!("❌ [Call] Rilascio partecipanti di {} fallito: {}", call_id, e);
+    }
+}
+
+/// Libera i partecipanti delle chiamate gestite da nodi non più attivi (crash o
+/// riavvio): le loro chiamate non esistono più
+pub async fn release_stale(db: &PgPool, live_nodes: &[Uuid]) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM call_participants WHERE NOT (node_id = ANY($1))")
+        .bind(live_nodes)
+        .execute(db)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Scrive la riga di call_history (l'id coincide con il call_id dei messaggi WebSocket)
+async fn record(db: &PgPool, call: &Call, outcome: CallOutcome, ended_at: DateTime<Utc>) {
+    let (Ok(caller_id), Ok(callee_id)) = (Uuid::parse_str(&call.caller_id), Uuid::parse_str(&call.callee_id)) else {
+        return;
+    };
+
+    let result = sqlx::query(
+        r#"
+        INSERT INTO call_history (id, caller_id, callee_id, call_type, duration, status, started_at, ended_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#
+    )
+    .bind(call.id)
+    .bind(caller_id)
+    .bind(callee_id)
+    .bind(call.call_type.as_str())
+    .bind(call.duration(ended_at))
+    .bind(outcome.as_str())
+    .bind(call.started_at)
+    .bind(ended_at)
+    .execute(db)
+    .await;
+
+    if let Err(e) = result {
+        tracing::error!("❌ [Call] Salvataggio chiamata {} fallito: {}", call.id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_busy_and_hang_up_rules() {
+        let calls = CallRegistry::default();
+        let now = Utc::now();
+
+        let InviteResult::Ringing(call) = calls.invite("a", "b", CallType::Video, true, now).await else {
+            panic!("expected ringing");
+        };
+        assert!(matches!(calls.invite("c", "b", CallType::Audio, true, now).await, InviteResult::Busy(_)));
+        assert!(matches!(calls.invite("a", "c", CallType::Audio, true, now).await, InviteResult::CallerBusy));
+
+        // Un chiamato invisibile risulta non disponibile anche se è occupato
+        assert!(matches!(calls.invite("c", "b", CallType::Audio, false, now).await, InviteResult::Unavailable(_)));
+
+        // Solo il chiamato risponde, solo il chiamante annulla
+        assert!(calls.accept(call.id, "a", now).await.is_none());
+        assert!(calls.hang_up(call.id, "b", Hangup::Cancel).await.is_none());
+
+        assert!(calls.accept(call.id, "b", now).await.is_some());
+        assert!(calls.expire(call.id).await.is_none());
+
+        let (ended, outcome) = calls.hang_up(call.id, "b", Hangup::End).await.unwrap();
+        assert_eq!(outcome, CallOutcome::Completed);
+        assert_eq!(ended.duration(now + chrono::Duration::seconds(42)), 42);
+
+        // Chiusa la chiamata entrambi sono di nuovo liberi
+        assert!(matches!(calls.invite("b", "a", CallType::Audio, true, now).await, InviteResult::Ringing(_)));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_while_ringing() {
+        let calls = CallRegistry::default();
+        let now = Utc::now();
+
+        calls.invite("a", "b", CallType::Audio, true, now).await;
+        let (_, outcome) = calls.drop_user("b").await.unwrap();
+        assert_eq!(outcome, CallOutcome::Missed);
+
+        calls.invite("a", "b", CallType::Audio, true, now).await;
+        let (_, outcome) = calls.drop_user("a").await.unwrap();
+        assert_eq!(outcome, CallOutcome::Cancelled);
+    }
+}
@@ -0,0 +1,176 @@
+use axum::{
+    extract::State,
+    Extension,
+    Json,
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::{Claims, UserResponse},
+    error::{AppError, AppJson, AppResult},
+    mailer::Mail,
+    models::User,
+    utils::{generate_token, hash_token, is_unique_violation},
+};
+
+/// Validità di un token di verifica email
+const VERIFICATION_TOKEN_TTL_HOURS: i64 = 24;
+
+#[derive(Debug, Deserialize)]
+pub struct SetEmailRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+/// Imposta (o cambia) l'email dell'utente e invia il token di verifica.
+/// L'indirizzo resta non verificato finché il token non viene confermato.
+pub async fn set_email(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    AppJson(payload): AppJson<SetEmailRequest>,
+) -> AppResult<Json<UserResponse>> {
+    let user_id = claims.user_id()?;
+
+    let email = normalize_email(&payload.email)
+        .ok_or_else(|| AppError::Validation("Invalid email address".to_string()))?;
+
+    // Controlla che l'email non sia già usata da un altro account
+    let taken = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM users WHERE email = $1 AND id <> $2)"
+    )
+    .bind(&email)
+    .bind(user_id)
+    .fetch_one(&state.db)
+    .await?;
+
+    if taken {
+        return Err(AppError::EmailTaken);
+    }
+
+    // Se l'email non cambia mantieni lo stato di verifica
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users
+        SET email_verified = (email IS NOT DISTINCT FROM $1 AND email_verified),
+            email = $1, updated_at = NOW()
+        WHERE id = $2
+        RETURNING *
+        "#
+    )
+    .bind(&email)
+    .bind(user_id)
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| {
+        if is_unique_violation(&e) {
+            AppError::EmailTaken
+        } else {
+            e.into()
+        }
+    })?;
+
+    if !user.email_verified {
+        send_verification(&state, &user, &email).await?;
+    }
+
+    Ok(Json(user.into()))
+}
+
+pub async fn verify_email(
+    State(state): State<Arc<AppState>>,
+    AppJson(payload): AppJson<VerifyEmailRequest>,
+) -> AppResult<Json<UserResponse>> {
+    // Consuma il token (monouso) in modo atomico
+    let (user_id, email) = sqlx::query_as::<_, (Uuid, String)>(
+        r#"
+        UPDATE email_verifications SET used_at = NOW()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING user_id, email
+        "#
+    )
+    .bind(hash_token(&payload.token))
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(AppError::InvalidOneTimeToken)?;
+
+    // Il token vale solo per l'indirizzo a cui è stato inviato
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users SET email_verified = TRUE, updated_at = NOW()
+        WHERE id = $1 AND email = $2
+        RETURNING *
+        "#
+    )
+    .bind(user_id)
+    .bind(&email)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(AppError::EmailChanged)?;
+
+    Ok(Json(user.into()))
+}
+
+async fn send_verification(state: &AppState, user: &User, email: &str) -> AppResult<()> {
+    let token = generate_token();
+    let expires_at = Utc::now() + Duration::hours(VERIFICATION_TOKEN_TTL_HOURS);
+
+    sqlx::query(
+        r#"
+        INSERT INTO email_verifications (id, user_id, email, token_hash, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(user.id)
+    .bind(email)
+    .bind(hash_token(&token))
+    .bind(expires_at)
+    .execute(&state.db)
+    .await?;
+
+    let mail = Mail {
+        to: email.to_string(),
+        subject: "GameCall - Verifica la tua email".to_string(),
+        body: format!(
+            "Ciao {},\n\nusa questo codice per verificare il tuo indirizzo email su GameCall:\n\n{}\n\nIl codice scade tra {} ore.",
+            user.username, token, VERIFICATION_TOKEN_TTL_HOURS
+        ),
+    };
+
+    if let Err(e) = state.mailer.send(mail).await {
+        tracing::error!("❌ [Mailer] Invio email di verifica fallito: {}", e);
+    }
+
+    Ok(())
+}
+
+/// Valida e normalizza (trim + minuscolo) un indirizzo email
+fn normalize_email(email: &str) -> Option<String> {
+    let email = email.trim().to_lowercase();
+    if email.len() > 255 {
+        return None;
+    }
+    email.parse::<lettre::Address>().ok()?;
+    Some(email)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_email() {
+        assert_eq!(normalize_email("  Mario@Example.COM "), Some("mario@example.com".to_string()));
+        assert_eq!(normalize_email("not-an-email"), None);
+        assert_eq!(normalize_email("@example.com"), None);
+        assert_eq!(normalize_email(&format!("{}@example.com", "a".repeat(250))), None);
+    }
+}
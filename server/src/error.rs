@@ -0,0 +1,332 @@
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        FromRequest, FromRequestParts, Query,
+    },
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub type AppResult<T> = Result<T, AppError>;
+
+/// Errore restituito dagli handler. Ogni variante ha un codice stabile che il
+/// client può usare al posto del messaggio; gli errori interni vengono loggati
+/// con un correlation id e al client arriva solo quell'id.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    // 400
+    #[error("{0}")]
+    Validation(String),
+    #[error("Invalid request body: {0}")]
+    InvalidBody(String),
+    #[error("Invalid query string: {0}")]
+    InvalidQuery(String),
+    #[error("Invalid {0}")]
+    InvalidId(&'static str),
+    #[error("Invalid or expired token")]
+    InvalidOneTimeToken,
+    #[error("Cannot add yourself as friend")]
+    CannotAddSelf,
+    #[error("Cannot block yourself")]
+    CannotBlockSelf,
+    #[error("Two-factor enrollment not started")]
+    MfaNotEnrolled,
+    #[error("Two-factor authentication not enabled")]
+    MfaNotEnabled,
+
+    // 401
+    #[error("Missing authorization")]
+    MissingAuthorization,
+    #[error("Invalid authorization format")]
+    InvalidAuthorizationFormat,
+    #[error("Invalid token")]
+    InvalidToken,
+    #[error("Session revoked")]
+    SessionRevoked,
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+    #[error("Current password is incorrect")]
+    WrongCurrentPassword,
+    #[error("Invalid refresh token")]
+    InvalidRefreshToken,
+    #[error("Refresh token reuse detected, session revoked")]
+    RefreshTokenReused,
+    #[error("Invalid or expired MFA token")]
+    InvalidMfaToken,
+    #[error("Invalid MFA code")]
+    InvalidMfaCode,
+    #[error("Invalid or expired WebSocket ticket")]
+    InvalidWsTicket,
+
+    // 403
+    #[error("Messages can only be exchanged with accepted friends")]
+    NotFriends,
+
+    // 404
+    #[error("User not found")]
+    UserNotFound,
+    #[error("Session not found")]
+    SessionNotFound,
+    #[error("Friend code not found")]
+    FriendCodeNotFound,
+    #[error("Friend request not found")]
+    FriendRequestNotFound,
+    #[error("Friendship not found")]
+    FriendshipNotFound,
+    #[error("User is not blocked")]
+    UserNotBlocked,
+
+    // 409
+    #[error("Username already exists")]
+    UsernameTaken,
+    #[error("Email already in use")]
+    EmailTaken,
+    #[error("Email address has changed since the token was sent")]
+    EmailChanged,
+    #[error("Friendship already exists")]
+    FriendshipExists,
+    #[error("Friend request already sent")]
+    FriendRequestExists,
+    #[error("You have blocked this user")]
+    UserBlocked,
+    #[error("Two-factor authentication already enabled")]
+    MfaAlreadyEnabled,
+
+    // 413 / 429
+    #[error("Request body too large")]
+    PayloadTooLarge,
+    #[error("Too many requests")]
+    RateLimited { retry_after: Duration },
+
+    // 500: il dettaglio resta nei log
+    #[error("Internal server error")]
+    Internal(String),
+
+    // 503
+    #[error("Server is shutting down")]
+    ShuttingDown,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub details: Option<serde_json::Value>,
+}
+
+impl AppError {
+    pub fn internal(e: impl std::fmt::Display) -> Self {
+        Self::Internal(e.to_string())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Validation(_)
+            | Self::InvalidBody(_)
+            | Self::InvalidQuery(_)
+            | Self::InvalidId(_)
+            | Self::InvalidOneTimeToken
+            | Self::CannotAddSelf
+            | Self::CannotBlockSelf
+            | Self::MfaNotEnrolled
+            | Self::MfaNotEnabled => StatusCode::BAD_REQUEST,
+            Self::MissingAuthorization
+            | Self::InvalidAuthorizationFormat
+            | Self::InvalidToken
+            | Self::SessionRevoked
+            | Self::InvalidCredentials
+            | Self::WrongCurrentPassword
+            | Self::InvalidRefreshToken
+            | Self::RefreshTokenReused
+            | Self::InvalidMfaToken
+            | Self::InvalidMfaCode
+            | Self::InvalidWsTicket => StatusCode::UNAUTHORIZED,
+            Self::NotFriends => StatusCode::FORBIDDEN,
+            Self::UserNotFound
+            | Self::SessionNotFound
+            | Self::FriendCodeNotFound
+            | Self::FriendRequestNotFound
+            | Self::FriendshipNotFound
+            | Self::UserNotBlocked => StatusCode::NOT_FOUND,
+            Self::UsernameTaken
+            | Self::EmailTaken
+            | Self::EmailChanged
+            | Self::FriendshipExists
+            | Self::FriendRequestExists
+            | Self::UserBlocked
+            | Self::MfaAlreadyEnabled => StatusCode::CONFLICT,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Codice stabile esposto al client
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Validation(_) => "VALIDATION_ERROR",
+            Self::InvalidBody(_) => "INVALID_BODY",
+            Self::InvalidQuery(_) => "INVALID_QUERY",
+            Self::InvalidId(_) => "INVALID_ID",
+            Self::InvalidOneTimeToken => "INVALID_ONE_TIME_TOKEN",
+            Self::CannotAddSelf => "CANNOT_ADD_SELF",
+            Self::CannotBlockSelf => "CANNOT_BLOCK_SELF",
+            Self::MfaNotEnrolled => "MFA_NOT_ENROLLED",
+            Self::MfaNotEnabled => "MFA_NOT_ENABLED",
+            Self::MissingAuthorization => "MISSING_AUTHORIZATION",
+            Self::InvalidAuthorizationFormat => "INVALID_AUTHORIZATION_FORMAT",
+            Self::InvalidToken => "INVALID_TOKEN",
+            Self::SessionRevoked => "SESSION_REVOKED",
+            Self::InvalidCredentials => "INVALID_CREDENTIALS",
+            Self::WrongCurrentPassword => "WRONG_CURRENT_PASSWORD",
+            Self::InvalidRefreshToken => "INVALID_REFRESH_TOKEN",
+            Self::RefreshTokenReused => "REFRESH_TOKEN_REUSED",
+            Self::InvalidMfaToken => "INVALID_MFA_TOKEN",
+            Self::InvalidMfaCode => "INVALID_MFA_CODE",
+            Self::InvalidWsTicket => "INVALID_WS_TICKET",
+            Self::NotFriends => "NOT_FRIENDS",
+            Self::UserNotFound => "USER_NOT_FOUND",
+            Self::SessionNotFound => "SESSION_NOT_FOUND",
+            Self::FriendCodeNotFound => "FRIEND_CODE_NOT_FOUND",
+            Self::FriendRequestNotFound => "FRIEND_REQUEST_NOT_FOUND",
+            Self::FriendshipNotFound => "FRIENDSHIP_NOT_FOUND",
+            Self::UserNotBlocked => "USER_NOT_BLOCKED",
+            Self::UsernameTaken => "USERNAME_TAKEN",
+            Self::EmailTaken => "EMAIL_TAKEN",
+            Self::EmailChanged => "EMAIL_CHANGED",
+            Self::FriendshipExists => "FRIENDSHIP_EXISTS",
+            Self::FriendRequestExists => "FRIEND_REQUEST_EXISTS",
+            Self::UserBlocked => "USER_BLOCKED",
+            Self::MfaAlreadyEnabled => "MFA_ALREADY_ENABLED",
+            Self::PayloadTooLarge => "PAYLOAD_TOO_LARGE",
+            Self::RateLimited { .. } => "RATE_LIMITED",
+            Self::Internal(_) => "INTERNAL_ERROR",
+            Self::ShuttingDown => "SHUTTING_DOWN",
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            Self::RateLimited { retry_after } => Some(serde_json::json!({
+                "retry_after": retry_after_secs(*retry_after),
+            })),
+            _ => None,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+
+        let body = if let Self::Internal(detail) = &self {
+            let correlation_id = Uuid::new_v4();
+            tracing::error!("❌ [Error] {} (correlation_id {})", detail, correlation_id);
+            ErrorBody {
+                code: self.code(),
+                message: self.to_string(),
+                details: Some(serde_json::json!({ "correlation_id": correlation_id })),
+            }
+        } else {
+            ErrorBody {
+                code: self.code(),
+                message: self.to_string(),
+                details: self.details(),
+            }
+        };
+
+        let mut response = (status, Json(body)).into_response();
+
+        if let Self::RateLimited { retry_after } = self {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                retry_after_secs(retry_after).into(),
+            );
+        }
+
+        response
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::internal(e)
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::InvalidBody(rejection.body_text())
+    }
+}
+
+/// Estrattore JSON per i body delle richieste: come `axum::Json` ma gli errori
+/// di parsing diventano `AppError` con body JSON
+#[derive(Debug, FromRequest)]
+#[from_request(via(Json), rejection(AppError))]
+pub struct AppJson<T>(pub T);
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::InvalidQuery(rejection.body_text())
+    }
+}
+
+/// Come `AppJson`, per i parametri della query string
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(Query), rejection(AppError))]
+pub struct AppQuery<T>(pub T);
+
+/// Retry-After è in secondi interi: arrotonda per eccesso, minimo 1
+fn retry_after_secs(retry_after: Duration) -> u64 {
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    seconds.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_error_body_shape() {
+        let response = AppError::FriendCodeNotFound.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = body_json(response).await;
+        assert_eq!(body["code"], "FRIEND_CODE_NOT_FOUND");
+        assert_eq!(body["message"], "Friend code not found");
+        assert!(body["details"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_internal_error_is_hidden() {
+        let response = AppError::internal("relation \"users\" does not exist").into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body = body_json(response).await;
+        assert_eq!(body["code"], "INTERNAL_ERROR");
+        assert_eq!(body["message"], "Internal server error");
+        assert!(!body.to_string().contains("does not exist"));
+        assert!(body["details"]["correlation_id"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_sets_retry_after() {
+        let response = AppError::RateLimited { retry_after: Duration::from_millis(1500) }.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+
+        let body = body_json(response).await;
+        assert_eq!(body["details"]["retry_after"], 2);
+    }
+}
@@ -4,11 +4,21 @@ use axum::{
     Extension,
     Json,
 };
+use chrono::{DateTime, Utc};
+use gamecall_protocol::{Activity, CustomStatus, ServerMessage};
 use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
 use std::sync::Arc;
 use uuid::Uuid;
 
-use crate::{AppState, auth::Claims, models::User, websocket::WsMessage};
+use crate::{
+    AppState,
+    auth::Claims,
+    error::{AppError, AppJson, AppResult},
+    models::User,
+    presence::PresenceView,
+    utils::is_unique_violation,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct AddFriendRequest {
@@ -20,6 +30,16 @@ pub struct FriendActionRequest {
     pub friendship_id: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct BlockRequest {
+    pub user_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FriendSettings {
+    pub auto_accept: bool,
+}
+
 #[derive(Debug, Serialize)]
 pub struct FriendResponse {
     pub id: String,
@@ -28,19 +48,21 @@ pub struct FriendResponse {
     pub avatar_url: Option<String>,
     pub status: String,
     pub friendship_status: String,
+    pub last_seen: Option<DateTime<Utc>>, // solo per gli amici offline
+    pub custom_status: Option<CustomStatus>,
+    pub activity: Option<Activity>,
 }
 
 pub async fn list_friends(
     State(state): State<Arc<AppState>>,
     Extension(claims): Extension<Claims>,
-) -> Result<Json<Vec<FriendResponse>>, (StatusCode, String)> {
-    let user_id = Uuid::parse_str(&claims.sub)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid user ID".to_string()))?;
+) -> AppResult<Json<Vec<FriendResponse>>> {
+    let user_id = claims.user_id()?;
 
     // Query per ottenere tutti gli amici accettati
-    let friends = sqlx::query_as::<_, (Uuid, String, String, Option<String>, String, String)>(
+    let friends = sqlx::query_as::<_, (Uuid, String, String, Option<String>, String, Option<DateTime<Utc>>)>(
         r#"
-        SELECT u.id, u.username, u.friend_code, u.avatar_url, u.status, f.status as friendship_status
+        SELECT u.id, u.username, u.friend_code, u.avatar_url, f.status as friendship_status, u.last_seen_at
         FROM friendships f
         JOIN users u ON (f.friend_id = u.id)
         WHERE f.user_id = $1 AND f.status = 'accepted'
@@ -48,29 +70,37 @@ pub async fn list_friends(
     )
     .bind(user_id)
     .fetch_all(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-    .into_iter()
-    .map(|(id, username, friend_code, avatar_url, status, friendship_status)| FriendResponse {
-        id: id.to_string(),
-        username,
-        friend_code,
-        avatar_url,
-        status,
-        friendship_status,
-    })
-    .collect();
+    .await?;
+
+    // La presenza arriva dalle connessioni WebSocket attive, non da users.status
+    // (gli utenti invisibili risultano offline)
+    let mut response = Vec::with_capacity(friends.len());
+    for (id, username, friend_code, avatar_url, friendship_status, last_seen_at) in friends {
+        let id = id.to_string();
+        let presence = state.ws_state.visible_presence(&id).await;
+        let online = presence.status != "offline";
+        response.push(FriendResponse {
+            id,
+            username,
+            friend_code,
+            avatar_url,
+            status: presence.status,
+            friendship_status,
+            last_seen: if online { None } else { last_seen_at },
+            custom_status: presence.custom_status,
+            activity: presence.activity,
+        });
+    }
 
-    Ok(Json(friends))
+    Ok(Json(response))
 }
 
 pub async fn add_friend(
     State(state): State<Arc<AppState>>,
     Extension(claims): Extension<Claims>,
-    Json(payload): Json<AddFriendRequest>,
-) -> Result<Json<FriendResponse>, (StatusCode, String)> {
-    let user_id = Uuid::parse_str(&claims.sub)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid user ID".to_string()))?;
+    AppJson(payload): AppJson<AddFriendRequest>,
+) -> AppResult<Json<FriendResponse>> {
+    let user_id = claims.user_id()?;
 
     // Trova utente con friend_code
     let friend = sqlx::query_as::<_, User>(
@@ -78,110 +108,175 @@ pub async fn add_friend(
     )
     .bind(&payload.friend_code)
     .fetch_optional(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-    .ok_or((StatusCode::NOT_FOUND, "Friend code not found".to_string()))?;
+    .await?
+    .ok_or(AppError::FriendCodeNotFound)?;
 
     // Non puoi aggiungere te stesso
     if friend.id == user_id {
-        return Err((StatusCode::BAD_REQUEST, "Cannot add yourself as friend".to_string()));
+        return Err(AppError::CannotAddSelf);
     }
 
-    // Controlla se già amici (controlla entrambe le direzioni)
-    let existing = sqlx::query_scalar::<_, i64>(
-        "SELECT COUNT(*) FROM friendships WHERE (user_id = $1 AND friend_id = $2) OR (user_id = $2 AND friend_id = $1)"
+    let mut tx = state.db.begin().await?;
+
+    // Righe esistenti in entrambe le direzioni (bloccate fino al commit)
+    let existing = sqlx::query_as::<_, (Uuid, String)>(
+        r#"
+        SELECT user_id, status FROM friendships
+        WHERE (user_id = $1 AND friend_id = $2) OR (user_id = $2 AND friend_id = $1)
+        FOR UPDATE
+        "#
     )
     .bind(user_id)
     .bind(friend.id)
-    .fetch_one(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .fetch_all(&mut *tx)
+    .await?;
 
-    if existing > 0 {
-        return Err((StatusCode::CONFLICT, "Friendship already exists".to_string()));
+    // Bloccato dal destinatario: rispondi come se il friend code non esistesse
+    if existing.iter().any(|(from, status)| status == "blocked" && *from == friend.id) {
+        return Err(AppError::FriendCodeNotFound);
     }
 
-    // Crea amicizia bidirezionale accettata automaticamente
-    let friendship_id_1 = Uuid::new_v4();
-    let friendship_id_2 = Uuid::new_v4();
+    if existing.iter().any(|(_, status)| status == "blocked") {
+        return Err(AppError::UserBlocked);
+    }
 
-    // Amicizia da user_id a friend_id
-    sqlx::query(
-        r#"
-        INSERT INTO friendships (id, user_id, friend_id, status)
-        VALUES ($1, $2, $3, 'accepted')
-        "#
-    )
-    .bind(friendship_id_1)
-    .bind(user_id)
-    .bind(friend.id)
-    .execute(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if existing.iter().any(|(_, status)| status == "accepted") {
+        return Err(AppError::FriendshipExists);
+    }
 
-    // Amicizia reciproca da friend_id a user_id
-    sqlx::query(
-        r#"
-        INSERT INTO friendships (id, user_id, friend_id, status)
-        VALUES ($1, $2, $3, 'accepted')
-        "#
-    )
-    .bind(friendship_id_2)
-    .bind(friend.id)
-    .bind(user_id)
-    .execute(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if existing.iter().any(|(from, _)| *from == user_id) {
+        return Err(AppError::FriendRequestExists);
+    }
+
+    // Richiesta già ricevuta da questo utente, oppure auto-accept attivo:
+    // l'amicizia diventa subito accettata in entrambe le direzioni
+    let incoming = !existing.is_empty();
+    let accepted = incoming || friend.friend_auto_accept;
+
+    if incoming {
+        sqlx::query(
+            "UPDATE friendships SET status = 'accepted' WHERE user_id = $1 AND friend_id = $2"
+        )
+        .bind(friend.id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    } else {
+        insert_friendship(&mut tx, friend.id, user_id, if accepted { "accepted" } else { "pending" }).await?;
+    }
+
+    if accepted {
+        insert_friendship(&mut tx, user_id, friend.id, "accepted").await?;
+    }
+
+    tx.commit().await?;
+
+    if accepted {
+        state.ws_state.invalidate_friends(&[user_id, friend.id]).await;
+        state.ws_state.exchange_presence(&user_id.to_string(), &friend.id.to_string()).await;
+    }
 
     // Ottieni informazioni dell'utente che ha aggiunto
-    let adder = sqlx::query_as::<_, User>(
-        "SELECT * FROM users WHERE id = $1"
-    )
-    .bind(user_id)
-    .fetch_one(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let adder = fetch_user(&state, user_id).await?;
 
     // Invia notifica WebSocket all'amico aggiunto
-    state.ws_state.send_to_user(
-        &friend.id.to_string(),
-        &WsMessage::FriendAdded {
+    let message = if incoming {
+        ServerMessage::FriendRequestAccepted {
             friend_id: user_id.to_string(),
-            friend_username: adder.username.clone(),
-            friend_code: adder.friend_code.clone(),
+            friend_username: adder.username,
+            friend_code: adder.friend_code,
         }
-    ).await;
+    } else if accepted {
+        ServerMessage::FriendAdded {
+            friend_id: user_id.to_string(),
+            friend_username: adder.username,
+            friend_code: adder.friend_code,
+        }
+    } else {
+        ServerMessage::FriendRequest {
+            from_user_id: user_id.to_string(),
+            from_username: adder.username,
+            from_friend_code: adder.friend_code,
+            from_avatar_url: adder.avatar_url,
+        }
+    };
+    state.ws_state.send_to_user(&friend.id.to_string(), &message).await;
+
+    // La presenza è visibile solo agli amici accettati
+    let presence = if accepted {
+        state.ws_state.visible_presence(&friend.id.to_string()).await
+    } else {
+        PresenceView::offline()
+    };
 
     Ok(Json(FriendResponse {
         id: friend.id.to_string(),
         username: friend.username,
         friend_code: friend.friend_code,
         avatar_url: friend.avatar_url,
-        status: friend.status,
-        friendship_status: "accepted".to_string(),
+        status: presence.status,
+        friendship_status: if accepted { "accepted" } else { "pending" }.to_string(),
+        last_seen: None,
+        custom_status: presence.custom_status,
+        activity: presence.activity,
     }))
 }
 
 pub async fn list_requests(
     State(state): State<Arc<AppState>>,
     Extension(claims): Extension<Claims>,
-) -> Result<Json<Vec<FriendResponse>>, (StatusCode, String)> {
-    let user_id = Uuid::parse_str(&claims.sub)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid user ID".to_string()))?;
+) -> AppResult<Json<Vec<FriendResponse>>> {
+    let user_id = claims.user_id()?;
 
     // Query per ottenere tutte le richieste in arrivo
     let requests = sqlx::query_as::<_, (Uuid, String, String, Option<String>, String, String)>(
         r#"
-        SELECT u.id, u.username, u.friend_code, u.avatar_url, u.status, f.status as friendship_status
+        SELECT u.id, u.username, u.friend_code, u.avatar_url, 'offline' as status, f.status as friendship_status
         FROM friendships f
         JOIN users u ON (f.user_id = u.id)
         WHERE f.friend_id = $1 AND f.status = 'pending'
+        ORDER BY f.created_at DESC
         "#
     )
     .bind(user_id)
     .fetch_all(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .await?
+    .into_iter()
+    .map(|(id, username, friend_code, avatar_url, status, friendship_status)| FriendResponse {
+        id: id.to_string(),
+        username,
+        friend_code,
+        avatar_url,
+        status,
+        friendship_status,
+        last_seen: None,
+        custom_status: None,
+        activity: None,
+    })
+    .collect();
+
+    Ok(Json(requests))
+}
+
+pub async fn list_outgoing_requests(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> AppResult<Json<Vec<FriendResponse>>> {
+    let user_id = claims.user_id()?;
+
+    // Richieste inviate e non ancora accettate/rifiutate
+    let requests = sqlx::query_as::<_, (Uuid, String, String, Option<String>, String, String)>(
+        r#"
+        SELECT u.id, u.username, u.friend_code, u.avatar_url, 'offline' as status, f.status as friendship_status
+        FROM friendships f
+        JOIN users u ON (f.friend_id = u.id)
+        WHERE f.user_id = $1 AND f.status = 'pending'
+        ORDER BY f.created_at DESC
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(&state.db)
+    .await?
     .into_iter()
     .map(|(id, username, friend_code, avatar_url, status, friendship_status)| FriendResponse {
         id: id.to_string(),
@@ -190,6 +285,9 @@ pub async fn list_requests(
         avatar_url,
         status,
         friendship_status,
+        last_seen: None,
+        custom_status: None,
+        activity: None,
     })
     .collect();
 
@@ -199,13 +297,14 @@ pub async fn list_requests(
 pub async fn accept_request(
     State(state): State<Arc<AppState>>,
     Extension(claims): Extension<Claims>,
-    Json(payload): Json<FriendActionRequest>,
-) -> Result<StatusCode, (StatusCode, String)> {
-    let user_id = Uuid::parse_str(&claims.sub)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid user ID".to_string()))?;
+    AppJson(payload): AppJson<FriendActionRequest>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
     
     let requester_id = Uuid::parse_str(&payload.friendship_id)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid friendship ID".to_string()))?;
+        .map_err(|_| AppError::InvalidId("friendship ID"))?;
+
+    let mut tx = state.db.begin().await?;
 
     // Aggiorna la richiesta a 'accepted'
     let result = sqlx::query(
@@ -217,28 +316,31 @@ pub async fn accept_request(
     )
     .bind(requester_id)
     .bind(user_id)
-    .execute(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .execute(&mut *tx)
+    .await?;
 
     if result.rows_affected() == 0 {
-        return Err((StatusCode::NOT_FOUND, "Friend request not found".to_string()));
+        return Err(AppError::FriendRequestNotFound);
     }
 
     // Crea amicizia reciproca
-    let friendship_id = Uuid::new_v4();
-    sqlx::query(
-        r#"
-        INSERT INTO friendships (id, user_id, friend_id, status)
-        VALUES ($1, $2, $3, 'accepted')
-        "#
-    )
-    .bind(friendship_id)
-    .bind(user_id)
-    .bind(requester_id)
-    .execute(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    insert_friendship(&mut tx, user_id, requester_id, "accepted").await?;
+
+    tx.commit().await?;
+
+    state.ws_state.invalidate_friends(&[user_id, requester_id]).await;
+    state.ws_state.exchange_presence(&user_id.to_string(), &requester_id.to_string()).await;
+
+    // Notifica il richiedente
+    let accepter = fetch_user(&state, user_id).await?;
+    state.ws_state.send_to_user(
+        &requester_id.to_string(),
+        &ServerMessage::FriendRequestAccepted {
+            friend_id: user_id.to_string(),
+            friend_username: accepter.username,
+            friend_code: accepter.friend_code,
+        }
+    ).await;
 
     Ok(StatusCode::OK)
 }
@@ -246,13 +348,12 @@ pub async fn accept_request(
 pub async fn reject_request(
     State(state): State<Arc<AppState>>,
     Extension(claims): Extension<Claims>,
-    Json(payload): Json<FriendActionRequest>,
-) -> Result<StatusCode, (StatusCode, String)> {
-    let user_id = Uuid::parse_str(&claims.sub)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid user ID".to_string()))?;
+    AppJson(payload): AppJson<FriendActionRequest>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
     
     let requester_id = Uuid::parse_str(&payload.friendship_id)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid friendship ID".to_string()))?;
+        .map_err(|_| AppError::InvalidId("friendship ID"))?;
 
     // Elimina la richiesta
     let result = sqlx::query(
@@ -261,44 +362,319 @@ pub async fn reject_request(
     .bind(requester_id)
     .bind(user_id)
     .execute(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .await?;
 
     if result.rows_affected() == 0 {
-        return Err((StatusCode::NOT_FOUND, "Friend request not found".to_string()));
+        return Err(AppError::FriendRequestNotFound);
     }
 
+    // Notifica il richiedente
+    state.ws_state.send_to_user(
+        &requester_id.to_string(),
+        &ServerMessage::FriendRequestRejected {
+            user_id: user_id.to_string(),
+        }
+    ).await;
+
     Ok(StatusCode::OK)
 }
 
+/// Annulla una richiesta inviata (friendship_id = id del destinatario)
+pub async fn cancel_request(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    AppJson(payload): AppJson<FriendActionRequest>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
+
+    let recipient_id = Uuid::parse_str(&payload.friendship_id)
+        .map_err(|_| AppError::InvalidId("friendship ID"))?;
+
+    let result = sqlx::query(
+        "DELETE FROM friendships WHERE user_id = $1 AND friend_id = $2 AND status = 'pending'"
+    )
+    .bind(user_id)
+    .bind(recipient_id)
+    .execute(&state.db)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::FriendRequestNotFound);
+    }
+
+    // Notifica il destinatario, così rimuove la richiesta dalla sua lista
+    state.ws_state.send_to_user(
+        &recipient_id.to_string(),
+        &ServerMessage::FriendRequestCancelled {
+            from_user_id: user_id.to_string(),
+        }
+    ).await;
+
+    Ok(StatusCode::OK)
+}
+
+/// Blocca un utente: elimina senza notifiche l'amicizia o le richieste tra i due
+pub async fn block_user(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    AppJson(payload): AppJson<BlockRequest>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
+
+    let blocked_id = Uuid::parse_str(&payload.user_id)
+        .map_err(|_| AppError::InvalidId("user ID"))?;
+
+    if blocked_id == user_id {
+        return Err(AppError::CannotBlockSelf);
+    }
+
+    let exists = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM users WHERE id = $1)"
+    )
+    .bind(blocked_id)
+    .fetch_one(&state.db)
+    .await?;
+
+    if !exists {
+        return Err(AppError::UserNotFound);
+    }
+
+    let mut tx = state.db.begin().await?;
+
+    // Il blocco nella direzione opposta (se l'altro utente ha bloccato noi) resta.
+    // L'amicizia si legge dalle righe cancellate, dentro la stessa transazione
+    let removed_statuses = sqlx::query_scalar::<_, String>(
+        r#"
+        DELETE FROM friendships
+        WHERE (user_id = $1 AND friend_id = $2)
+           OR (user_id = $2 AND friend_id = $1 AND status <> 'blocked')
+        RETURNING status
+        "#
+    )
+    .bind(user_id)
+    .bind(blocked_id)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let were_friends = removed_statuses.iter().any(|status| status == "accepted");
+
+    insert_friendship(&mut tx, user_id, blocked_id, "blocked").await?;
+
+    tx.commit().await?;
+
+    state.ws_state.invalidate_friends(&[user_id, blocked_id]).await;
+
+    // Senza amicizia non c'è nulla da togliere: né presenza né lista amici
+    if were_friends {
+        // Gli altri dispositivi di chi blocca tolgono l'utente dalla lista amici
+        state.ws_state.send_to_user(
+            &user_id.to_string(),
+            &ServerMessage::FriendRemoved {
+                friend_id: blocked_id.to_string(),
+            }
+        ).await;
+
+        // L'utente bloccato ci vede andare offline e rimuovere l'amicizia
+        // (come per /friends/remove), senza sapere del blocco
+        state.ws_state.send_to_user(
+            &blocked_id.to_string(),
+            &ServerMessage::UserOffline {
+                user_id: user_id.to_string(),
+            }
+        ).await;
+
+        state.ws_state.send_to_user(
+            &blocked_id.to_string(),
+            &ServerMessage::FriendRemoved {
+                friend_id: user_id.to_string(),
+            }
+        ).await;
+    }
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn unblock_user(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    AppJson(payload): AppJson<BlockRequest>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
+
+    let blocked_id = Uuid::parse_str(&payload.user_id)
+        .map_err(|_| AppError::InvalidId("user ID"))?;
+
+    let result = sqlx::query(
+        "DELETE FROM friendships WHERE user_id = $1 AND friend_id = $2 AND status = 'blocked'"
+    )
+    .bind(user_id)
+    .bind(blocked_id)
+    .execute(&state.db)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::UserNotBlocked);
+    }
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn list_blocked(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> AppResult<Json<Vec<FriendResponse>>> {
+    let user_id = claims.user_id()?;
+
+    let blocked = sqlx::query_as::<_, (Uuid, String, String, Option<String>, String, String)>(
+        r#"
+        SELECT u.id, u.username, u.friend_code, u.avatar_url, 'offline' as status, f.status as friendship_status
+        FROM friendships f
+        JOIN users u ON (f.friend_id = u.id)
+        WHERE f.user_id = $1 AND f.status = 'blocked'
+        ORDER BY f.created_at DESC
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(&state.db)
+    .await?
+    .into_iter()
+    .map(|(id, username, friend_code, avatar_url, status, friendship_status)| FriendResponse {
+        id: id.to_string(),
+        username,
+        friend_code,
+        avatar_url,
+        status,
+        friendship_status,
+        last_seen: None,
+        custom_status: None,
+        activity: None,
+    })
+    .collect();
+
+    Ok(Json(blocked))
+}
+
+/// Amici accettati di `user_id`: gli unici che ne vedono la presenza
+pub async fn accepted_friend_ids(db: &PgPool, user_id: Uuid) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        "SELECT friend_id FROM friendships WHERE user_id = $1 AND status = 'accepted'"
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await
+}
+
+/// Amicizia accettata tra `user_id` e `friend_id` (chat e storico solo tra amici)
+pub async fn are_friends(db: &PgPool, user_id: Uuid, friend_id: Uuid) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM friendships WHERE user_id = $1 AND friend_id = $2 AND status = 'accepted')"
+    )
+    .bind(user_id)
+    .bind(friend_id)
+    .fetch_one(db)
+    .await
+}
+
+/// Imposta l'accettazione automatica delle richieste di amicizia
+pub async fn update_settings(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    AppJson(payload): AppJson<FriendSettings>,
+) -> AppResult<Json<FriendSettings>> {
+    let user_id = claims.user_id()?;
+
+    let auto_accept = sqlx::query_scalar::<_, bool>(
+        r#"
+        UPDATE users SET friend_auto_accept = $1, updated_at = NOW()
+        WHERE id = $2
+        RETURNING friend_auto_accept
+        "#
+    )
+    .bind(payload.auto_accept)
+    .bind(user_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(AppError::UserNotFound)?;
+
+    Ok(Json(FriendSettings { auto_accept }))
+}
+
 pub async fn remove_friend(
     State(state): State<Arc<AppState>>,
     Extension(claims): Extension<Claims>,
-    Json(payload): Json<FriendActionRequest>,
-) -> Result<StatusCode, (StatusCode, String)> {
-    let user_id = Uuid::parse_str(&claims.sub)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid user ID".to_string()))?;
+    AppJson(payload): AppJson<FriendActionRequest>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
     
     let friend_id = Uuid::parse_str(&payload.friendship_id)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid friendship ID".to_string()))?;
+        .map_err(|_| AppError::InvalidId("friendship ID"))?;
 
-    // Elimina entrambe le amicizie (bidirezionale)
-    sqlx::query(
-        "DELETE FROM friendships WHERE (user_id = $1 AND friend_id = $2) OR (user_id = $2 AND friend_id = $1)"
+    // Elimina entrambe le amicizie (bidirezionale); richieste pendenti e blocchi restano
+    let result = sqlx::query(
+        r#"
+        DELETE FROM friendships
+        WHERE ((user_id = $1 AND friend_id = $2) OR (user_id = $2 AND friend_id = $1))
+          AND status = 'accepted'
+        "#
     )
     .bind(user_id)
     .bind(friend_id)
     .execute(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::FriendshipNotFound);
+    }
+
+    state.ws_state.invalidate_friends(&[user_id, friend_id]).await;
 
     // Invia notifica WebSocket all'amico rimosso
     state.ws_state.send_to_user(
         &friend_id.to_string(),
-        &WsMessage::FriendRemoved {
+        &ServerMessage::FriendRemoved {
             friend_id: user_id.to_string(),
         }
     ).await;
 
     Ok(StatusCode::OK)
 }
+
+async fn insert_friendship(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    friend_id: Uuid,
+    status: &str,
+) -> AppResult<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO friendships (id, user_id, friend_id, status)
+        VALUES ($1, $2, $3, $4)
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(friend_id)
+    .bind(status)
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| {
+        // Richiesta concorrente tra gli stessi utenti
+        if is_unique_violation(&e) {
+            AppError::FriendshipExists
+        } else {
+            e.into()
+        }
+    })?;
+
+    Ok(())
+}
+
+async fn fetch_user(state: &AppState, user_id: Uuid) -> AppResult<User> {
+    sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(AppError::UserNotFound)
+}
@@ -0,0 +1,358 @@
+use axum::{extract::State, Json};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    errors::{Error as JwtError, ErrorKind},
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::AppState;
+
+/// Chiavi per firmare e verificare i JWT.
+///
+/// Con HS256 si usa il solo `JWT_SECRET` (deploy esistenti). Con EdDSA/RS256 le
+/// chiavi stanno in `JWT_KEY_DIR`: ogni `<kid>.pub.pem` è una chiave di verifica
+/// attiva, mentre `JWT_SIGNING_KID` indica quale `<kid>.key.pem` usare per firmare.
+/// Per ruotare si aggiunge la nuova coppia, si cambia `JWT_SIGNING_KID` e si rimuove
+/// la vecchia chiave pubblica solo dopo che i token firmati con essa sono scaduti.
+#[derive(Clone)]
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    signing_kid: Option<String>,
+    encoding_key: EncodingKey,
+    decoding_keys: HashMap<String, DecodingKey>,
+    // Secret HS256 per i token senza kid (HS256, o token emessi prima della migrazione)
+    legacy_key: Option<DecodingKey>,
+    jwks: JwkSet,
+}
+
+impl JwtKeys {
+    /// Legge JWT_ALGORITHM (HS256 di default), JWT_SECRET, JWT_KEY_DIR e JWT_SIGNING_KID
+    pub fn from_env() -> Result<Self, String> {
+        let algorithm = std::env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string());
+        let secret = std::env::var("JWT_SECRET").ok();
+
+        match algorithm.as_str() {
+            "HS256" => {
+                let secret = secret.ok_or("JWT_SECRET must be set")?;
+                Ok(Self::hs256(&secret))
+            }
+            "EdDSA" | "RS256" => {
+                let algorithm = if algorithm == "EdDSA" { Algorithm::EdDSA } else { Algorithm::RS256 };
+                let dir = std::env::var("JWT_KEY_DIR").map_err(|_| "JWT_KEY_DIR must be set")?;
+                let kid = std::env::var("JWT_SIGNING_KID").map_err(|_| "JWT_SIGNING_KID must be set")?;
+                Self::from_dir(algorithm, Path::new(&dir), &kid, secret.as_deref())
+            }
+            other => Err(format!("Unsupported JWT_ALGORITHM: {}", other)),
+        }
+    }
+
+    pub fn hs256(secret: &str) -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            signing_kid: None,
+            encoding_key: EncodingKey::from_secret(secret.as_ref()),
+            decoding_keys: HashMap::new(),
+            legacy_key: Some(DecodingKey::from_secret(secret.as_ref())),
+            jwks: JwkSet { keys: Vec::new() },
+        }
+    }
+
+    pub fn from_dir(
+        algorithm: Algorithm,
+        dir: &Path,
+        signing_kid: &str,
+        legacy_secret: Option<&str>,
+    ) -> Result<Self, String> {
+        let mut decoding_keys = HashMap::new();
+        let mut jwks = Vec::new();
+
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| format!("Cannot read JWT_KEY_DIR {}: {}", dir.display(), e))?;
+
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            let Some(kid) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".pub.pem"))
+            else {
+                continue;
+            };
+
+            let pem = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let (decoding_key, params) = public_key(algorithm, &pem)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+            jwks.push(Jwk {
+                common: CommonParameters {
+                    public_key_use: Some(PublicKeyUse::Signature),
+                    key_algorithm: Some(key_algorithm(algorithm)),
+                    key_id: Some(kid.to_string()),
+                    ..Default::default()
+                },
+                algorithm: params,
+            });
+            decoding_keys.insert(kid.to_string(), decoding_key);
+        }
+
+        if !decoding_keys.contains_key(signing_kid) {
+            return Err(format!("Missing public key {}.pub.pem for JWT_SIGNING_KID", signing_kid));
+        }
+
+        let private_path = dir.join(format!("{}.key.pem", signing_kid));
+        let private_pem = std::fs::read(&private_path)
+            .map_err(|e| format!("{}: {}", private_path.display(), e))?;
+        let encoding_key = match algorithm {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(&private_pem),
+            _ => EncodingKey::from_rsa_pem(&private_pem),
+        }
+        .map_err(|e| format!("{}: {}", private_path.display(), e))?;
+
+        // Ordine stabile nel JWKS
+        jwks.sort_by(|a, b| a.common.key_id.cmp(&b.common.key_id));
+
+        tracing::info!(
+            "🔑 [Auth] JWT {:?} firmati con kid {}, {} chiavi di verifica",
+            algorithm,
+            signing_kid,
+            decoding_keys.len()
+        );
+
+        Ok(Self {
+            algorithm,
+            signing_kid: Some(signing_kid.to_string()),
+            encoding_key,
+            decoding_keys,
+            legacy_key: legacy_secret.map(|secret| DecodingKey::from_secret(secret.as_ref())),
+            jwks: JwkSet { keys: jwks },
+        })
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, JwtError> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.signing_kid.clone();
+        encode(&header, claims, &self.encoding_key)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<T, JwtError> {
+        let header = decode_header(token)?;
+
+        let (key, algorithm) = match &header.kid {
+            Some(kid) => (
+                self.decoding_keys.get(kid).ok_or(ErrorKind::InvalidSignature)?,
+                self.algorithm,
+            ),
+            None => (
+                self.legacy_key.as_ref().ok_or(ErrorKind::InvalidSignature)?,
+                Algorithm::HS256,
+            ),
+        };
+
+        decode::<T>(token, key, &Validation::new(algorithm)).map(|data| data.claims)
+    }
+}
+
+/// GET /.well-known/jwks.json - chiavi pubbliche per verificare i token
+pub async fn jwks(State(state): State<Arc<AppState>>) -> Json<JwkSet> {
+    Json(state.jwt_keys.jwks.clone())
+}
+
+fn key_algorithm(algorithm: Algorithm) -> KeyAlgorithm {
+    match algorithm {
+        Algorithm::EdDSA => KeyAlgorithm::EdDSA,
+        _ => KeyAlgorithm::RS256,
+    }
+}
+
+/// Legge una chiave pubblica PEM (SubjectPublicKeyInfo, o PKCS#1 per RSA)
+/// e restituisce la chiave di verifica e i parametri per il JWKS
+fn public_key(algorithm: Algorithm, pem_bytes: &[u8]) -> Result<(DecodingKey, AlgorithmParameters), String> {
+    let pem = pem::parse(pem_bytes).map_err(|e| e.to_string())?;
+
+    match (algorithm, pem.tag()) {
+        (Algorithm::EdDSA, "PUBLIC KEY") => {
+            let key = spki_key_bytes(pem.contents()).ok_or("Invalid Ed25519 public key")?;
+            if key.len() != 32 {
+                return Err("Invalid Ed25519 public key".to_string());
+            }
+            let x = URL_SAFE_NO_PAD.encode(key);
+            let decoding_key = DecodingKey::from_ed_components(&x).map_err(|e| e.to_string())?;
+            Ok((
+                decoding_key,
+                AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x,
+                }),
+            ))
+        }
+        (Algorithm::RS256, "PUBLIC KEY" | "RSA PUBLIC KEY") => {
+            let pkcs1 = if pem.tag() == "PUBLIC KEY" {
+                spki_key_bytes(pem.contents()).ok_or("Invalid RSA public key")?
+            } else {
+                pem.contents()
+            };
+            let (n, e) = rsa_components(pkcs1).ok_or("Invalid RSA public key")?;
+            Ok((
+                DecodingKey::from_rsa_raw_components(n, e),
+                AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: URL_SAFE_NO_PAD.encode(n),
+                    e: URL_SAFE_NO_PAD.encode(e),
+                }),
+            ))
+        }
+        (_, tag) => Err(format!("Unexpected PEM block {} for {:?}", tag, algorithm)),
+    }
+}
+
+// Minimo lettore DER: restituisce (tag, contenuto, resto)
+fn der_read(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count].iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (len, &rest[count..])
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+/// SubjectPublicKeyInfo ::= SEQUENCE { algorithm SEQUENCE, subjectPublicKey BIT STRING }
+fn spki_key_bytes(der: &[u8]) -> Option<&[u8]> {
+    let (0x30, spki, _) = der_read(der)? else { return None };
+    let (0x30, _, rest) = der_read(spki)? else { return None };
+    let (0x03, bits, _) = der_read(rest)? else { return None };
+    // Il primo byte della BIT STRING è il numero di bit inutilizzati
+    match bits.split_first()? {
+        (0, key) => Some(key),
+        _ => None,
+    }
+}
+
+/// RSAPublicKey ::= SEQUENCE { modulus INTEGER, publicExponent INTEGER }
+fn rsa_components(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (0x30, seq, _) = der_read(der)? else { return None };
+    let (0x02, n, rest) = der_read(seq)? else { return None };
+    let (0x02, e, _) = der_read(rest)? else { return None };
+    Some((strip_leading_zeros(n), strip_leading_zeros(e)))
+}
+
+fn strip_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct TestClaims {
+        sub: String,
+        exp: i64,
+    }
+
+    fn claims() -> TestClaims {
+        TestClaims {
+            sub: "user".to_string(),
+            exp: chrono::Utc::now().timestamp() + 60,
+        }
+    }
+
+    // Scrive <kid>.key.pem (PKCS#8) e <kid>.pub.pem (SPKI) per una nuova chiave Ed25519
+    fn write_ed25519_key(dir: &Path, kid: &str) {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+
+        // Prefisso DER di una SubjectPublicKeyInfo Ed25519 (OID 1.3.101.112)
+        let mut spki = vec![0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+        spki.extend_from_slice(pair.public_key().as_ref());
+
+        let private = pem::encode(&pem::Pem::new("PRIVATE KEY", pkcs8.as_ref().to_vec()));
+        let public = pem::encode(&pem::Pem::new("PUBLIC KEY", spki));
+        std::fs::write(dir.join(format!("{}.key.pem", kid)), private).unwrap();
+        std::fs::write(dir.join(format!("{}.pub.pem", kid)), public).unwrap();
+    }
+
+    fn temp_key_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("gamecall-keys-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_hs256_roundtrip() {
+        let keys = JwtKeys::hs256("secret");
+        let token = keys.encode(&claims()).unwrap();
+
+        assert!(decode_header(&token).unwrap().kid.is_none());
+        assert_eq!(keys.decode::<TestClaims>(&token).unwrap().sub, "user");
+        assert!(JwtKeys::hs256("other").decode::<TestClaims>(&token).is_err());
+        assert!(keys.jwks.keys.is_empty());
+    }
+
+    #[test]
+    fn test_eddsa_rotation_and_jwks() {
+        let dir = temp_key_dir();
+        write_ed25519_key(&dir, "2024-01");
+
+        let old_keys = JwtKeys::from_dir(Algorithm::EdDSA, &dir, "2024-01", None).unwrap();
+        let old_token = old_keys.encode(&claims()).unwrap();
+        assert_eq!(decode_header(&old_token).unwrap().kid.as_deref(), Some("2024-01"));
+
+        // Rotazione: nuova chiave di firma, la vecchia resta valida in verifica
+        write_ed25519_key(&dir, "2024-06");
+        let keys = JwtKeys::from_dir(Algorithm::EdDSA, &dir, "2024-06", Some("legacy")).unwrap();
+        let new_token = keys.encode(&claims()).unwrap();
+
+        assert_eq!(decode_header(&new_token).unwrap().kid.as_deref(), Some("2024-06"));
+        assert!(keys.decode::<TestClaims>(&new_token).is_ok());
+        assert!(keys.decode::<TestClaims>(&old_token).is_ok());
+
+        // Token HS256 emessi prima della migrazione
+        let legacy_token = JwtKeys::hs256("legacy").encode(&claims()).unwrap();
+        assert!(keys.decode::<TestClaims>(&legacy_token).is_ok());
+        assert!(old_keys.decode::<TestClaims>(&legacy_token).is_err());
+
+        let jwks = serde_json::to_value(&keys.jwks).unwrap();
+        let kids: Vec<_> = jwks["keys"].as_array().unwrap().iter().map(|k| k["kid"].clone()).collect();
+        assert_eq!(kids, vec!["2024-01", "2024-06"]);
+        assert_eq!(jwks["keys"][0]["kty"], "OKP");
+        assert_eq!(jwks["keys"][0]["crv"], "Ed25519");
+        assert_eq!(jwks["keys"][0]["alg"], "EdDSA");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_rsa_components_from_pkcs1() {
+        // RSAPublicKey con n = 0x00C3 (zero iniziale di segno) ed e = 65537
+        let der = [0x30, 0x09, 0x02, 0x02, 0x00, 0xc3, 0x02, 0x03, 0x01, 0x00, 0x01];
+        let (n, e) = rsa_components(&der).unwrap();
+        assert_eq!(n, &[0xc3]);
+        assert_eq!(e, &[0x01, 0x00, 0x01]);
+    }
+}
@@ -0,0 +1,158 @@
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox,
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// Email da inviare
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Trasporto per l'invio delle email (SMTP in produzione, file/log in locale e nei test)
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> Result<(), String>;
+}
+
+/// Invio tramite server SMTP (STARTTLS)
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, port: u16, username: String, password: String, from: &str) -> Result<Self, String> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .map_err(|e| e.to_string())?
+            .port(port)
+            .credentials(Credentials::new(username, password))
+            .build();
+
+        let from = from.parse::<Mailbox>().map_err(|e| e.to_string())?;
+
+        Ok(Self { transport, from })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: Mail) -> Result<(), String> {
+        let to = mail.to.parse::<Mailbox>().map_err(|e| e.to_string())?;
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(mail.subject)
+            .body(mail.body)
+            .map_err(|e| e.to_string())?;
+
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+}
+
+/// Non invia nulla: scrive le email nel log e, se configurato, in append su un file
+pub struct FileMailer {
+    path: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: Mail) -> Result<(), String> {
+        tracing::info!("📧 [Mailer] Email a {}: {}", mail.to, mail.subject);
+
+        if let Some(path) = &self.path {
+            let entry = format!(
+                "To: {}\nSubject: {}\n\n{}\n---\n",
+                mail.to, mail.subject, mail.body
+            );
+
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            file.write_all(entry.as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Crea il mailer in base a MAIL_TRANSPORT ("smtp" oppure "file", default)
+pub fn from_env() -> Arc<dyn Mailer> {
+    let transport = std::env::var("MAIL_TRANSPORT").unwrap_or_else(|_| "file".to_string());
+
+    if transport == "smtp" {
+        let host = std::env::var("SMTP_HOST").expect("SMTP_HOST must be set");
+        let port = std::env::var("SMTP_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(587);
+        let username = std::env::var("SMTP_USERNAME").expect("SMTP_USERNAME must be set");
+        let password = std::env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD must be set");
+        let from = std::env::var("MAIL_FROM").expect("MAIL_FROM must be set");
+
+        let mailer = SmtpMailer::new(&host, port, username, password, &from)
+            .expect("invalid SMTP configuration");
+
+        tracing::info!("📧 [Mailer] SMTP via {}:{}", host, port);
+        Arc::new(mailer)
+    } else {
+        let path = std::env::var("MAIL_LOG_PATH").ok().map(PathBuf::from);
+        tracing::info!("📧 [Mailer] Email scritte su log/file ({:?})", path);
+        Arc::new(FileMailer::new(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_mailer_appends_to_file() {
+        let path = std::env::temp_dir().join(format!("gamecall-mail-{}.log", uuid::Uuid::new_v4()));
+        let mailer = FileMailer::new(Some(path.clone()));
+
+        for subject in ["Primo", "Secondo"] {
+            mailer
+                .send(Mail {
+                    to: "mario@example.com".to_string(),
+                    subject: subject.to_string(),
+                    body: "codice: 1234".to_string(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let content = tokio::fs::read_to_string(&path).await.unwrap();
+        let _ = tokio::fs::remove_file(&path).await;
+
+        assert!(content.contains("To: mario@example.com"));
+        assert!(content.contains("Subject: Primo"));
+        assert!(content.contains("Subject: Secondo"));
+        assert!(content.contains("codice: 1234"));
+    }
+}
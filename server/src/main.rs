@@ -1,25 +1,48 @@
 use axum::{
     middleware as axum_middleware,
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
 use sqlx::postgres::PgPoolOptions;
+use std::future::IntoFuture;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tower_http::cors::{Any, CorsLayer};
-use tracing_subscriber;
 
 mod auth;
+mod calls;
+mod email;
+mod error;
 mod friends;
+mod jwt;
+mod mailer;
+mod messages;
+mod mfa;
 mod middleware;
 mod models;
+mod outbox;
+mod password;
+mod presence;
+mod pubsub;
+mod rate_limit;
+mod send_queue;
+mod sessions;
+mod totp;
 mod utils;
 mod websocket;
+mod ws_ticket;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: sqlx::PgPool,
-    pub jwt_secret: String,
+    pub jwt_keys: jwt::JwtKeys,
     pub ws_state: websocket::WsState,
+    pub mailer: Arc<dyn mailer::Mailer>,
+    // Accetta ancora il JWT in /ws?token= (client precedenti ai ticket)
+    pub ws_query_token: bool,
+    // IP del client dagli header del proxy (Fly-Client-IP / X-Forwarded-For)
+    pub trust_proxy_headers: bool,
 }
 
 #[tokio::main]
@@ -33,10 +56,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Leggi configurazione
     let database_url = std::env::var("DATABASE_URL")
         .expect("DATABASE_URL must be set");
-    let jwt_secret = std::env::var("JWT_SECRET")
-        .expect("JWT_SECRET must be set");
+    let jwt_keys = jwt::JwtKeys::from_env()
+        .expect("invalid JWT configuration");
     let port = std::env::var("PORT")
         .unwrap_or_else(|_| "3000".to_string());
+    // Porta interna per /metrics (non servito se manca)
+    let metrics_port = std::env::var("METRICS_PORT").ok();
+    let trust_proxy_headers = utils::trust_proxy_headers_from_env();
+    // JWT in /ws?token= solo per i client vecchi, se abilitato esplicitamente
+    let ws_query_token = std::env::var("WS_ALLOW_QUERY_TOKEN")
+        .is_ok_and(|value| value == "true");
+    if ws_query_token {
+        tracing::warn!("WS_ALLOW_QUERY_TOKEN is enabled: /ws accepts a JWT in the query string (deprecated)");
+    }
+    // Attesa massima per richieste HTTP in corso e chiusura dei WebSocket allo shutdown
+    let shutdown_timeout = std::env::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map_or(Duration::from_secs(10), Duration::from_secs);
 
     // Connessione al database
     let pool = PgPoolOptions::new()
@@ -46,16 +83,42 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tracing::info!("Connected to database");
 
-    // Crea WebSocket state
-    let ws_state = websocket::WsState::new();
+    // Migrazioni idempotenti: aggiornano anche i database creati con schema.sql
+    sqlx::migrate!("./migrations")
+        .run(&pool)
+        .await?;
+
+    // Crea WebSocket state (con più istanze i nodi comunicano via LISTEN/NOTIFY)
+    let ws_state = websocket::WsState::new(pool.clone())
+        .with_heartbeat(websocket::HeartbeatConfig::from_env())
+        .with_pubsub(pubsub::from_env(&pool).await?);
+    ws_state.start();
+    outbox::start_pruning(pool.clone());
 
     // State condiviso
     let state = Arc::new(AppState {
         db: pool,
-        jwt_secret,
+        jwt_keys,
         ws_state: ws_state.clone(),
+        mailer: mailer::from_env(),
+        ws_query_token,
+        trust_proxy_headers,
     });
 
+    // Rate limiting (in memoria, per singola istanza)
+    let rate_limits = Arc::new(rate_limit::MemoryRateLimitStore::new());
+    {
+        let rate_limits = rate_limits.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                rate_limits.prune(Duration::from_secs(60 * 60));
+            }
+        });
+    }
+    let rate_limited = |rule| rate_limit::RateLimitLayer::new(rate_limits.clone(), rule, trust_proxy_headers);
+
     // Setup CORS
     let cors = CorsLayer::new()
         .allow_origin(Any)
@@ -67,19 +130,55 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route("/ws", get(websocket::ws_handler))
         .layer(axum_middleware::from_fn_with_state(
             state.clone(),
-            middleware::auth_middleware,
+            middleware::ws_auth_middleware,
         ))
-        .with_state(ws_state);
+        .with_state(ws_state.clone());
 
     // Protected routes (require JWT)
     let protected = Router::new()
         .route("/auth/me", get(auth::me))
+        .route("/auth/logout", post(auth::logout))
+        .route(
+            "/auth/password",
+            post(password::change_password).layer(rate_limited(rate_limit::RateLimitRule::per_ip("password_change", 5.0))),
+        )
+        .route("/auth/email", post(email::set_email))
+        .route("/auth/mfa/totp/enroll", post(mfa::enroll_totp))
+        .route("/auth/mfa/totp/confirm", post(mfa::confirm_totp))
+        .route("/auth/mfa/totp/disable", post(mfa::disable_totp))
+        .route("/auth/sessions", get(sessions::list_sessions))
+        .route("/auth/sessions/:id", delete(sessions::delete_session))
         .route("/friends", get(friends::list_friends))
-        .route("/friends/add", post(friends::add_friend))
+        .route(
+            "/friends/add",
+            post(friends::add_friend).layer(rate_limited(rate_limit::RateLimitRule::friend_lookup())),
+        )
         .route("/friends/requests", get(friends::list_requests))
+        .route("/friends/requests/outgoing", get(friends::list_outgoing_requests))
         .route("/friends/accept", post(friends::accept_request))
         .route("/friends/reject", post(friends::reject_request))
+        .route("/friends/cancel", post(friends::cancel_request))
+        .route("/friends/settings", put(friends::update_settings))
         .route("/friends/remove", post(friends::remove_friend))
+        .route("/friends/block", post(friends::block_user))
+        .route("/friends/unblock", post(friends::unblock_user))
+        .route("/friends/blocked", get(friends::list_blocked))
+        .route("/ws/ticket", post(ws_ticket::create_ticket))
+        .route(
+            "/presence",
+            get(presence::get_presence)
+                .merge(put(presence::update_presence).layer(rate_limited(rate_limit::RateLimitRule::presence()))),
+        )
+        .route("/calls", get(calls::list_calls))
+        .route("/calls/missed/count", get(calls::missed_count))
+        .route("/calls/missed/seen", post(calls::mark_missed_seen))
+        .route(
+            "/messages",
+            post(messages::send_message).layer(rate_limited(rate_limit::RateLimitRule::messages())),
+        )
+        .route("/conversations", get(messages::list_conversations))
+        .route("/conversations/:friend_id/messages", get(messages::list_messages))
+        .route("/conversations/:friend_id/read", post(messages::mark_read))
         .layer(axum_middleware::from_fn_with_state(
             state.clone(),
             middleware::auth_middleware,
@@ -88,9 +187,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Setup router
     let app = Router::new()
         .route("/health", get(health_check))
+        .route("/.well-known/jwks.json", get(jwt::jwks))
         // Auth routes (public)
-        .route("/auth/register", post(auth::register))
-        .route("/auth/login", post(auth::login))
+        .route(
+            "/auth/register",
+            post(auth::register).layer(rate_limited(rate_limit::RateLimitRule::per_ip("register", 5.0))),
+        )
+        .route(
+            "/auth/login",
+            post(auth::login).layer(rate_limited(rate_limit::RateLimitRule::login())),
+        )
+        .route(
+            "/auth/login/mfa",
+            post(mfa::login_mfa).layer(rate_limited(rate_limit::RateLimitRule::login_mfa())),
+        )
+        .route(
+            "/auth/refresh",
+            post(auth::refresh).layer(rate_limited(rate_limit::RateLimitRule::per_ip("refresh", 30.0))),
+        )
+        .route(
+            "/auth/password/forgot",
+            post(password::forgot_password).layer(rate_limited(rate_limit::RateLimitRule::per_ip("password_forgot", 5.0))),
+        )
+        .route(
+            "/auth/password/reset",
+            post(password::reset_password).layer(rate_limited(rate_limit::RateLimitRule::per_ip("password_reset", 5.0))),
+        )
+        .route(
+            "/auth/email/verify",
+            post(email::verify_email).layer(rate_limited(rate_limit::RateLimitRule::per_ip("email_verify", 5.0))),
+        )
         // Merge protected routes
         .merge(protected)
         // Merge WebSocket route
@@ -103,12 +229,78 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await?;
     
     tracing::info!("Server listening on port {}", port);
-    
-    axum::serve(listener, app).await?;
 
+    // Le metriche hanno un listener separato, da non esporre pubblicamente
+    if let Some(metrics_port) = metrics_port {
+        let metrics_app = Router::new()
+            .route("/metrics", get(websocket::metrics))
+            .with_state(ws_state.clone());
+        let metrics_listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", metrics_port))
+            .await?;
+
+        tracing::info!("Metrics listening on port {}", metrics_port);
+        tokio::spawn(axum::serve(metrics_listener, metrics_app).into_future());
+    }
+
+    // Allo shutdown il server smette di accettare connessioni e attende le richieste in corso
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let mut server = tokio::spawn(
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async {
+            let _ = stop_rx.await;
+        })
+        .into_future(),
+    );
+
+    tokio::select! {
+        _ = shutdown_signal() => {}
+        result = &mut server => return Ok(result??),
+    }
+
+    tracing::info!("Shutting down (timeout {:?})", shutdown_timeout);
+    let _ = stop_tx.send(());
+
+    // I WebSocket non sono richieste HTTP in corso: vengono chiusi a parte, in parallelo
+    let (_, http) = tokio::join!(
+        ws_state.shutdown(shutdown_timeout),
+        tokio::time::timeout(shutdown_timeout, &mut server),
+    );
+    match http {
+        Ok(result) => result??,
+        Err(_) => tracing::warn!("Shutdown timeout reached, aborting in-flight requests"),
+    }
+
+    tracing::info!("Server stopped");
     Ok(())
 }
 
+/// SIGTERM (deploy) oppure Ctrl+C
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
 async fn health_check() -> &'static str {
     "OK"
 }
@@ -0,0 +1,285 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use gamecall_protocol::{DirectMessage, ServerMessage};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::Claims,
+    error::{AppError, AppJson, AppQuery, AppResult},
+    friends,
+};
+
+/// Lunghezza massima di un messaggio (in caratteri)
+const MAX_CONTENT_CHARS: usize = 4000;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct SendMessageRequest {
+    pub recipient_id: Uuid,
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    // Id del messaggio più vecchio già ricevuto (next_cursor della pagina precedente)
+    pub before: Option<Uuid>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryResponse {
+    pub messages: Vec<DirectMessage>, // in ordine cronologico
+    pub next_cursor: Option<String>,  // None = inizio della conversazione
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConversationResponse {
+    pub friend_id: String,
+    pub friend_username: String,
+    pub friend_avatar_url: Option<String>,
+    pub last_message: DirectMessage,
+    pub unread_count: i64,
+}
+
+#[derive(Debug, FromRow)]
+struct MessageRow {
+    id: Uuid,
+    sender_id: Uuid,
+    recipient_id: Uuid,
+    content: String,
+    created_at: DateTime<Utc>,
+    read_at: Option<DateTime<Utc>>,
+}
+
+impl From<MessageRow> for DirectMessage {
+    fn from(row: MessageRow) -> Self {
+        Self {
+            id: row.id.to_string(),
+            sender_id: row.sender_id.to_string(),
+            recipient_id: row.recipient_id.to_string(),
+            content: row.content,
+            created_at: row.created_at,
+            read_at: row.read_at,
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct ConversationRow {
+    friend_id: Uuid,
+    friend_username: String,
+    friend_avatar_url: Option<String>,
+    #[sqlx(flatten)]
+    last_message: MessageRow,
+    unread_count: i64,
+}
+
+/// Invia un messaggio diretto a un amico: salvato e consegnato via WebSocket
+pub async fn send_message(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    AppJson(payload): AppJson<SendMessageRequest>,
+) -> AppResult<(StatusCode, Json<DirectMessage>)> {
+    let user_id = claims.user_id()?;
+    let content = validate_content(&payload.content)?;
+
+    if !friends::are_friends(&state.db, user_id, payload.recipient_id).await? {
+        return Err(AppError::NotFriends);
+    }
+
+    let row = sqlx::query_as::<_, MessageRow>(
+        r#"
+        INSERT INTO messages (sender_id, recipient_id, content)
+        VALUES ($1, $2, $3)
+        RETURNING id, sender_id, recipient_id, content, created_at, read_at
+        "#
+    )
+    .bind(user_id)
+    .bind(payload.recipient_id)
+    .bind(content)
+    .fetch_one(&state.db)
+    .await?;
+
+    let message = DirectMessage::from(row);
+    let event = ServerMessage::MessageNew { message: message.clone() };
+
+    // Anche al mittente, per gli altri suoi dispositivi
+    state.ws_state.send_to_user(&message.recipient_id, &event).await;
+    state.ws_state.send_to_user(&message.sender_id, &event).await;
+
+    Ok((StatusCode::CREATED, Json(message)))
+}
+
+/// Storico di una conversazione, dal più recente, a pagine
+pub async fn list_messages(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    Path(friend_id): Path<String>,
+    AppQuery(query): AppQuery<HistoryQuery>,
+) -> AppResult<Json<HistoryResponse>> {
+    let user_id = claims.user_id()?;
+    let friend_id = Uuid::parse_str(&friend_id)
+        .map_err(|_| AppError::InvalidId("friend ID"))?;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    if !friends::are_friends(&state.db, user_id, friend_id).await? {
+        return Err(AppError::NotFriends);
+    }
+
+    // Il cursore deve essere un messaggio di questa conversazione
+    let cursor = match query.before {
+        Some(before) => Some(
+            sqlx::query_as::<_, (DateTime<Utc>, Uuid)>(
+                r#"
+                SELECT created_at, id FROM messages
+                WHERE id = $3
+                  AND ((sender_id = $1 AND recipient_id = $2) OR (sender_id = $2 AND recipient_id = $1))
+                "#
+            )
+            .bind(user_id)
+            .bind(friend_id)
+            .bind(before)
+            .fetch_optional(&state.db)
+            .await?
+            .ok_or(AppError::InvalidId("cursor"))?,
+        ),
+        None => None,
+    };
+    let (cursor_created_at, cursor_id) = cursor.unzip();
+
+    // Una riga in più per sapere se esistono messaggi più vecchi
+    let mut rows = sqlx::query_as::<_, MessageRow>(
+        r#"
+        SELECT id, sender_id, recipient_id, content, created_at, read_at
+        FROM messages
+        WHERE ((sender_id = $1 AND recipient_id = $2) OR (sender_id = $2 AND recipient_id = $1))
+          AND ($3::timestamptz IS NULL OR (created_at, id) < ($3, $4))
+        ORDER BY created_at DESC, id DESC
+        LIMIT $5
+        "#
+    )
+    .bind(user_id)
+    .bind(friend_id)
+    .bind(cursor_created_at)
+    .bind(cursor_id)
+    .bind(limit + 1)
+    .fetch_all(&state.db)
+    .await?;
+
+    let next_cursor = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|row| row.id.to_string())
+    } else {
+        None
+    };
+
+    let messages = rows.into_iter().rev().map(DirectMessage::from).collect();
+
+    Ok(Json(HistoryResponse { messages, next_cursor }))
+}
+
+/// Conversazioni con gli amici, con ultimo messaggio e messaggi non letti
+pub async fn list_conversations(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> AppResult<Json<Vec<ConversationResponse>>> {
+    let user_id = claims.user_id()?;
+
+    let rows = sqlx::query_as::<_, ConversationRow>(
+        r#"
+        WITH last AS (
+            SELECT DISTINCT ON (friend_id)
+                   CASE WHEN m.sender_id = $1 THEN m.recipient_id ELSE m.sender_id END AS friend_id,
+                   m.id, m.sender_id, m.recipient_id, m.content, m.created_at, m.read_at
+            FROM messages m
+            WHERE m.sender_id = $1 OR m.recipient_id = $1
+            ORDER BY friend_id, m.created_at DESC, m.id DESC
+        )
+        SELECT l.friend_id, u.username AS friend_username, u.avatar_url AS friend_avatar_url,
+               l.id, l.sender_id, l.recipient_id, l.content, l.created_at, l.read_at,
+               (SELECT COUNT(*) FROM messages
+                WHERE sender_id = l.friend_id AND recipient_id = $1 AND read_at IS NULL) AS unread_count
+        FROM last l
+        JOIN users u ON u.id = l.friend_id
+        JOIN friendships f ON f.user_id = $1 AND f.friend_id = l.friend_id AND f.status = 'accepted'
+        ORDER BY l.created_at DESC
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    let conversations = rows
+        .into_iter()
+        .map(|row| ConversationResponse {
+            friend_id: row.friend_id.to_string(),
+            friend_username: row.friend_username,
+            friend_avatar_url: row.friend_avatar_url,
+            last_message: row.last_message.into(),
+            unread_count: row.unread_count,
+        })
+        .collect();
+
+    Ok(Json(conversations))
+}
+
+/// Segna come letti i messaggi ricevuti da un amico
+pub async fn mark_read(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    Path(friend_id): Path<String>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
+    let friend_id = Uuid::parse_str(&friend_id)
+        .map_err(|_| AppError::InvalidId("friend ID"))?;
+
+    sqlx::query(
+        "UPDATE messages SET read_at = NOW() WHERE sender_id = $1 AND recipient_id = $2 AND read_at IS NULL"
+    )
+    .bind(friend_id)
+    .bind(user_id)
+    .execute(&state.db)
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn validate_content(content: &str) -> AppResult<&str> {
+    let content = content.trim();
+
+    if content.is_empty() {
+        return Err(AppError::Validation("Message cannot be empty".to_string()));
+    }
+    if content.chars().count() > MAX_CONTENT_CHARS {
+        return Err(AppError::Validation(format!(
+            "Message must be at most {} characters",
+            MAX_CONTENT_CHARS
+        )));
+    }
+
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_content() {
+        assert_eq!(validate_content("  ciao \n").unwrap(), "ciao");
+        assert!(validate_content("   ").is_err());
+        assert!(validate_content(&"è".repeat(MAX_CONTENT_CHARS)).is_ok());
+        assert!(validate_content(&"è".repeat(MAX_CONTENT_CHARS + 1)).is_err());
+    }
+}
@@ -0,0 +1,383 @@
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    Extension,
+    Json,
+};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::{complete_login, hash_password, verify_password, AuthResponse, Claims},
+    error::{AppError, AppJson, AppResult},
+    models::User,
+    sessions::DeviceInfo,
+    totp,
+    utils::generate_recovery_code,
+};
+
+/// Validità del token intermedio tra password e codice 2FA
+const MFA_TOKEN_TTL_MINUTES: i64 = 5;
+
+const MFA_TOKEN_PURPOSE: &str = "mfa";
+
+/// Codici provabili con lo stesso mfa_token, poi serve di nuovo la password
+const MAX_MFA_ATTEMPTS: i32 = 5;
+
+/// Numero di codici di recupero generati all'attivazione
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Claims del token "mfa pending": non contiene sid né username,
+/// quindi non è accettato come access token dal middleware
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaClaims {
+    pub sub: String, // user_id
+    pub jti: String, // id della riga in mfa_challenges
+    pub purpose: String,
+    pub device_name: Option<String>,
+    pub exp: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaLoginRequest {
+    pub mfa_token: String,
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableTotpRequest {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpEnrollResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+pub async fn create_mfa_token(state: &AppState, user: &User, device_name: Option<String>) -> AppResult<String> {
+    let challenge_id = Uuid::new_v4();
+    let expires_at = Utc::now() + Duration::minutes(MFA_TOKEN_TTL_MINUTES);
+
+    // Pulizia delle challenge scadute
+    sqlx::query("DELETE FROM mfa_challenges WHERE expires_at < NOW()")
+        .execute(&state.db)
+        .await?;
+
+    sqlx::query("INSERT INTO mfa_challenges (id, user_id, expires_at) VALUES ($1, $2, $3)")
+        .bind(challenge_id)
+        .bind(user.id)
+        .bind(expires_at)
+        .execute(&state.db)
+        .await?;
+
+    let claims = MfaClaims {
+        sub: user.id.to_string(),
+        jti: challenge_id.to_string(),
+        purpose: MFA_TOKEN_PURPOSE.to_string(),
+        device_name,
+        exp: expires_at.timestamp(),
+    };
+
+    state.jwt_keys.encode(&claims)
+        .map_err(AppError::internal)
+}
+
+/// Secondo passaggio del login: codice TOTP oppure codice di recupero
+pub async fn login_mfa(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    AppJson(payload): AppJson<MfaLoginRequest>,
+) -> AppResult<Json<AuthResponse>> {
+    let claims = state.jwt_keys.decode::<MfaClaims>(&payload.mfa_token)
+        .map_err(|_| AppError::InvalidMfaToken)?;
+
+    if claims.purpose != MFA_TOKEN_PURPOSE {
+        return Err(AppError::InvalidMfaToken);
+    }
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::InvalidMfaToken)?;
+
+    let challenge_id = Uuid::parse_str(&claims.jti)
+        .map_err(|_| AppError::InvalidMfaToken)?;
+
+    // Ogni codice provato consuma un tentativo (atomico anche con richieste in parallelo);
+    // finiti i tentativi il token non è più valido
+    let reserved = sqlx::query(
+        r#"
+        UPDATE mfa_challenges SET attempts = attempts + 1
+        WHERE id = $1 AND user_id = $2 AND expires_at > NOW() AND attempts < $3
+        "#
+    )
+    .bind(challenge_id)
+    .bind(user_id)
+    .bind(MAX_MFA_ATTEMPTS)
+    .execute(&state.db)
+    .await?;
+
+    if reserved.rows_affected() == 0 {
+        return Err(AppError::InvalidMfaToken);
+    }
+
+    let user = fetch_user(&state, user_id).await?;
+
+    if !user.totp_enabled {
+        return Err(AppError::InvalidMfaToken);
+    }
+
+    if !check_second_factor(&state, &user, &payload.code).await? {
+        return Err(AppError::InvalidMfaCode);
+    }
+
+    // Token monouso: dopo il login non può aprire altre sessioni
+    sqlx::query("DELETE FROM mfa_challenges WHERE id = $1")
+        .bind(challenge_id)
+        .execute(&state.db)
+        .await?;
+
+    let device = DeviceInfo::from_request(&headers, addr, state.trust_proxy_headers, claims.device_name);
+    Ok(Json(complete_login(&state, user, device).await?))
+}
+
+/// Genera un nuovo segreto TOTP (non ancora attivo finché non viene confermato)
+pub async fn enroll_totp(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> AppResult<Json<TotpEnrollResponse>> {
+    let user_id = claims.user_id()?;
+
+    let secret = totp::encode_secret(&totp::generate_secret());
+
+    let result = sqlx::query(
+        "UPDATE users SET totp_secret = $1, updated_at = NOW() WHERE id = $2 AND totp_enabled = FALSE"
+    )
+    .bind(&secret)
+    .bind(user_id)
+    .execute(&state.db)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::MfaAlreadyEnabled);
+    }
+
+    Ok(Json(TotpEnrollResponse {
+        otpauth_uri: totp::provisioning_uri(&secret, &claims.username),
+        secret,
+    }))
+}
+
+/// Attiva la 2FA verificando un primo codice e restituisce i codici di recupero
+pub async fn confirm_totp(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    AppJson(payload): AppJson<TotpCodeRequest>,
+) -> AppResult<Json<RecoveryCodesResponse>> {
+    let user_id = claims.user_id()?;
+
+    let user = fetch_user(&state, user_id).await?;
+
+    if user.totp_enabled {
+        return Err(AppError::MfaAlreadyEnabled);
+    }
+
+    if user.totp_secret.is_none() {
+        return Err(AppError::MfaNotEnrolled);
+    }
+
+    if !check_totp(&state, &user, &payload.code).await? {
+        return Err(AppError::InvalidMfaCode);
+    }
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+
+    let mut tx = state.db.begin()
+        .await?;
+
+    sqlx::query("UPDATE users SET totp_enabled = TRUE, updated_at = NOW() WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    // Codici a bassa entropia e di lunga durata: argon2 con salt come per le password
+    for code in &recovery_codes {
+        sqlx::query("INSERT INTO recovery_codes (id, user_id, code_hash) VALUES ($1, $2, $3)")
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(hash_password(&normalize_recovery_code(code))?)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit()
+        .await?;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+/// Disattiva la 2FA: richiede password e un codice (TOTP o di recupero)
+pub async fn disable_totp(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    AppJson(payload): AppJson<DisableTotpRequest>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
+
+    let user = fetch_user(&state, user_id).await?;
+
+    if !user.totp_enabled {
+        return Err(AppError::MfaNotEnabled);
+    }
+
+    if !verify_password(&payload.password, &user.password_hash)? {
+        return Err(AppError::InvalidCredentials);
+    }
+
+    if !check_second_factor(&state, &user, &payload.code).await? {
+        return Err(AppError::InvalidMfaCode);
+    }
+
+    let mut tx = state.db.begin()
+        .await?;
+
+    sqlx::query(
+        r#"
+        UPDATE users
+        SET totp_enabled = FALSE, totp_secret = NULL, totp_last_step = NULL, updated_at = NOW()
+        WHERE id = $1
+        "#
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit()
+        .await?;
+
+    Ok(StatusCode::OK)
+}
+
+async fn fetch_user(state: &AppState, user_id: Uuid) -> AppResult<User> {
+    sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(AppError::UserNotFound)
+}
+
+/// Accetta un codice TOTP oppure, in alternativa, un codice di recupero (che viene consumato)
+async fn check_second_factor(state: &AppState, user: &User, code: &str) -> AppResult<bool> {
+    if check_totp(state, user, code).await? {
+        return Ok(true);
+    }
+
+    // Con il salt non si può cercare per hash: si confronta con ogni codice non usato
+    let unused = sqlx::query_as::<_, (Uuid, String)>(
+        "SELECT id, code_hash FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL"
+    )
+    .bind(user.id)
+    .fetch_all(&state.db)
+    .await?;
+
+    let code = normalize_recovery_code(code);
+    let mut matched = None;
+    for (id, code_hash) in unused {
+        if verify_password(&code, &code_hash)? {
+            matched = Some(id);
+            break;
+        }
+    }
+
+    let Some(id) = matched else {
+        return Ok(false);
+    };
+
+    // Condizionale: lo stesso codice usato in parallelo vale una sola volta
+    let result = sqlx::query("UPDATE recovery_codes SET used_at = NOW() WHERE id = $1 AND used_at IS NULL")
+        .bind(id)
+        .execute(&state.db)
+        .await?;
+
+    if result.rows_affected() > 0 {
+        tracing::info!("🔑 [Auth] Codice di recupero usato da {}", user.id);
+    }
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Verifica un codice TOTP rifiutando quelli già usati (stesso passo o precedenti)
+async fn check_totp(state: &AppState, user: &User, code: &str) -> AppResult<bool> {
+    let Some(secret) = user.totp_secret.as_deref().and_then(totp::decode_secret) else {
+        return Ok(false);
+    };
+
+    let Some(step) = totp::verify(&secret, code, Utc::now().timestamp() as u64) else {
+        return Ok(false);
+    };
+
+    let result = sqlx::query(
+        r#"
+        UPDATE users SET totp_last_step = $2
+        WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)
+        "#
+    )
+    .bind(user.id)
+    .bind(step as i64)
+    .execute(&state.db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// I codici di recupero si accettano senza trattino e in minuscolo
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recovery_code_matches() {
+        let code = normalize_recovery_code("abcde-fghjk");
+        assert_eq!(code, "ABCDEFGHJK");
+
+        let salted = hash_password(&code).unwrap();
+        assert!(verify_password(&code, &salted).unwrap());
+        assert!(!verify_password("ZZZZZZZZZZ", &salted).unwrap());
+    }
+}
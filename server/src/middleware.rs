@@ -1,59 +1,104 @@
 use axum::{
     extract::{Request, State},
-    http::{header, StatusCode},
+    http::header,
     middleware::Next,
     response::Response,
 };
-use jsonwebtoken::{decode, DecodingKey, Validation};
 use std::sync::Arc;
 
-use crate::{auth::Claims, AppState};
+use crate::{
+    auth::Claims,
+    error::{AppError, AppResult},
+    sessions,
+    ws_ticket::{self, WsIdentity},
+    AppState,
+};
 
-/// Middleware per estrarre e validare JWT token
+/// Middleware per estrarre e validare JWT token (solo header Authorization)
 pub async fn auth_middleware(
     State(state): State<Arc<AppState>>,
     mut req: Request,
     next: Next,
-) -> Result<Response, (StatusCode, String)> {
-    // Prova prima con header Authorization
-    let token = if let Some(auth_header) = req
+) -> AppResult<Response> {
+    let auth_header = req
         .headers()
         .get(header::AUTHORIZATION)
         .and_then(|h| h.to_str().ok())
-    {
-        // Verifica formato "Bearer <token>"
-        auth_header
-            .strip_prefix("Bearer ")
-            .ok_or((StatusCode::UNAUTHORIZED, "Invalid authorization format".to_string()))?
-            .to_string()
-    } else {
-        // Se non c'è header, prova con query parameter (per WebSocket)
-        let uri = req.uri();
-        let query = uri.query().unwrap_or("");
-
-        query
-            .split('&')
-            .find_map(|param| {
-                let mut parts = param.split('=');
-                if parts.next() == Some("token") {
-                    parts.next().map(|t| t.to_string())
-                } else {
-                    None
-                }
-            })
-            .ok_or((StatusCode::UNAUTHORIZED, "Missing authorization".to_string()))?
-    };
+        .ok_or(AppError::MissingAuthorization)?;
+
+    // Verifica formato "Bearer <token>"
+    let token = auth_header
+        .strip_prefix("Bearer ")
+        .ok_or(AppError::InvalidAuthorizationFormat)?;
 
-    // Valida JWT
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(state.jwt_secret.as_ref()),
-        &Validation::default(),
-    )
-    .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Invalid token: {}", e)))?;
+    let claims = validate_jwt(&state, token).await?;
 
     // Inserisci claims nella request per usarli nei handler
-    req.extensions_mut().insert(token_data.claims);
+    req.extensions_mut().insert(claims);
+
+    Ok(next.run(req).await)
+}
+
+/// Middleware per /ws: il browser non può impostare header sul WebSocket, quindi si
+/// usa un ticket monouso in `?ticket=` (vedi POST /ws/ticket). Il JWT in `?token=`
+/// (deprecato) è accettato per i client vecchi solo con WS_ALLOW_QUERY_TOKEN=true.
+pub async fn ws_auth_middleware(
+    State(state): State<Arc<AppState>>,
+    mut req: Request,
+    next: Next,
+) -> AppResult<Response> {
+    let identity = if let Some(ticket) = query_param(&req, "ticket") {
+        ws_ticket::consume(&state.db, ticket)
+            .await?
+            .ok_or(AppError::InvalidWsTicket)?
+    } else if let (true, Some(token)) = (state.ws_query_token, query_param(&req, "token")) {
+        tracing::warn!("⚠️ [WebSocket] Autenticazione con JWT nella query string (deprecata, client vecchio)");
+        WsIdentity::from(validate_jwt(&state, token).await?)
+    } else {
+        return Err(AppError::MissingAuthorization);
+    };
+
+    req.extensions_mut().insert(identity);
 
     Ok(next.run(req).await)
 }
+
+// Valida il JWT e verifica che la sessione non sia stata revocata (logout o furto del refresh token)
+async fn validate_jwt(state: &AppState, token: &str) -> AppResult<Claims> {
+    let claims = state.jwt_keys.decode::<Claims>(token)
+        .map_err(|_| AppError::InvalidToken)?;
+
+    let session_id = claims.session_id()?;
+
+    let active = sessions::is_session_active(&state.db, session_id)
+        .await?;
+
+    if !active {
+        return Err(AppError::SessionRevoked);
+    }
+
+    Ok(claims)
+}
+
+fn query_param<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.uri()
+        .query()?
+        .split('&')
+        .find_map(|param| param.split_once('=').filter(|(key, _)| *key == name).map(|(_, value)| value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_param() {
+        let req = Request::builder()
+            .uri("/ws?foo=1&ticket=abc123")
+            .body(axum::body::Body::empty())
+            .unwrap();
+
+        assert_eq!(query_param(&req, "ticket"), Some("abc123"));
+        assert_eq!(query_param(&req, "token"), None);
+    }
+}
@@ -1,3 +1,6 @@
+// Alcuni modelli rispecchiano tabelle non ancora usate dagli handler
+#![allow(dead_code)]
+
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use uuid::Uuid;
@@ -9,9 +12,23 @@ pub struct User {
     pub username: String,
     #[serde(skip_serializing)]
     pub password_hash: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    #[serde(skip_serializing)]
+    pub totp_last_step: Option<i64>,
     pub friend_code: String,
     pub avatar_url: Option<String>,
     pub status: String,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub friend_auto_accept: bool,
+    pub outbox_seq: i64,
+    pub presence: String,
+    pub custom_status_text: Option<String>,
+    pub custom_status_emoji: Option<String>,
+    pub custom_status_expires_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -47,4 +64,21 @@ pub struct CallHistory {
     pub status: String,
     pub started_at: DateTime<Utc>,
     pub ended_at: Option<DateTime<Utc>>,
+    pub seen_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    #[serde(skip_serializing)]
+    pub refresh_token_hash: String,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub ws_acked_seq: i64,
 }
@@ -0,0 +1,134 @@
+use gamecall_protocol::ServerMessage;
+use sqlx::PgPool;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Gli eventi restano nell'outbox al massimo per questo periodo, anche se non confermati
+const RETENTION_DAYS: i32 = 30;
+
+/// Ogni quanto vengono eliminati gli eventi confermati (non a ogni ack)
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Salva un evento nell'outbox dell'utente e restituisce il suo numero di sequenza
+/// (progressivo per utente, condiviso da tutti i dispositivi)
+pub async fn append(db: &PgPool, user_id: Uuid, message: &ServerMessage) -> Result<i64, sqlx::Error> {
+    let payload = serde_json::to_string(message).unwrap();
+
+    sqlx::query_scalar::<_, i64>(
+        r#"
+        WITH next AS (
+            UPDATE users SET outbox_seq = outbox_seq + 1 WHERE id = $1 RETURNING outbox_seq
+        )
+        INSERT INTO ws_outbox (user_id, seq, payload)
+        SELECT $1, outbox_seq, $2 FROM next
+        RETURNING seq
+        "#
+    )
+    .bind(user_id)
+    .bind(payload)
+    .fetch_one(db)
+    .await
+}
+
+/// Eventi successivi a `after`, in ordine
+pub async fn since(db: &PgPool, user_id: Uuid, after: i64) -> Result<Vec<(i64, String)>, sqlx::Error> {
+    sqlx::query_as::<_, (i64, String)>(
+        "SELECT seq, payload FROM ws_outbox WHERE user_id = $1 AND seq > $2 ORDER BY seq"
+    )
+    .bind(user_id)
+    .bind(after)
+    .fetch_all(db)
+    .await
+}
+
+/// Ultimo numero di sequenza assegnato all'utente
+pub async fn current_seq(db: &PgPool, user_id: Uuid) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>("SELECT outbox_seq FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(db)
+        .await
+}
+
+/// Ultima sequenza confermata dalla sessione (punto di ripresa se il client non lo indica)
+pub async fn acked_seq(db: &PgPool, session_id: Uuid) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>("SELECT ws_acked_seq FROM sessions WHERE id = $1")
+        .bind(session_id)
+        .fetch_optional(db)
+        .await
+        .map(Option::unwrap_or_default)
+}
+
+/// Registra la conferma di una sessione (gli eventi vengono eliminati da `start_pruning`)
+pub async fn ack(db: &PgPool, user_id: Uuid, session_id: Uuid, seq: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE sessions SET ws_acked_seq = GREATEST(ws_acked_seq, $1) WHERE id = $2 AND user_id = $3"
+    )
+    .bind(seq)
+    .bind(session_id)
+    .bind(user_id)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Elimina gli eventi già confermati da tutte le sessioni attive del loro utente (o più
+/// vecchi del periodo di conservazione)
+async fn prune(db: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM ws_outbox o
+        WHERE o.created_at < NOW() - make_interval(days => $1)
+           OR o.seq <= (
+                  SELECT MIN(s.ws_acked_seq) FROM sessions s
+                  WHERE s.user_id = o.user_id AND s.revoked_at IS NULL AND s.expires_at > NOW()
+              )
+        "#
+    )
+    .bind(RETENTION_DAYS)
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Pulizia periodica dell'outbox (ogni nodo la esegue, la DELETE è idempotente)
+pub fn start_pruning(db: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match prune(&db).await {
+                Ok(0) => {}
+                Ok(pruned) => tracing::info!("🧹 [Outbox] {} eventi eliminati", pruned),
+                Err(e) => tracing::error!("❌ [Outbox] Pulizia fallita: {}", e),
+            }
+        }
+    });
+}
+
+/// Aggiunge `seq` al JSON di un evento
+pub fn with_seq(payload: &str, seq: i64) -> String {
+    match serde_json::from_str::<serde_json::Value>(payload) {
+        Ok(serde_json::Value::Object(mut fields)) => {
+            fields.insert("seq".to_string(), seq.into());
+            serde_json::Value::Object(fields).to_string()
+        }
+        _ => payload.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_seq() {
+        let framed = with_seq(r#"{"type":"friend_removed","friend_id":"abc"}"#, 7);
+        let value: serde_json::Value = serde_json::from_str(&framed).unwrap();
+
+        assert_eq!(value["type"], "friend_removed");
+        assert_eq!(value["friend_id"], "abc");
+        assert_eq!(value["seq"], 7);
+    }
+}
@@ -0,0 +1,181 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    Extension,
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::{hash_password, validate_password, verify_password, Claims},
+    error::{AppError, AppJson, AppResult},
+    mailer::Mail,
+    models::User,
+    sessions,
+    utils::{generate_token, hash_token},
+};
+
+/// Validità di un token di reset della password
+const RESET_TOKEN_TTL_MINUTES: i64 = 30;
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+pub async fn change_password(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    AppJson(payload): AppJson<ChangePasswordRequest>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
+    let session_id = claims.session_id()?;
+
+    validate_password(&payload.new_password)?;
+
+    let user = sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(AppError::UserNotFound)?;
+
+    if !verify_password(&payload.current_password, &user.password_hash)? {
+        return Err(AppError::WrongCurrentPassword);
+    }
+
+    set_password(&state, user_id, &payload.new_password, Some(session_id)).await?;
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn forgot_password(
+    State(state): State<Arc<AppState>>,
+    AppJson(payload): AppJson<ForgotPasswordRequest>,
+) -> AppResult<StatusCode> {
+    let user = sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE username = $1"
+    )
+    .bind(&payload.username)
+    .fetch_optional(&state.db)
+    .await?;
+
+    // Risposta identica anche se l'utente non esiste, per non rivelare quali account esistono
+    let Some(user) = user else {
+        return Ok(StatusCode::ACCEPTED);
+    };
+
+    let Some(to) = recovery_address(&user) else {
+        tracing::warn!("⚠️ [Auth] Reset password richiesto per {} senza indirizzo di recupero", user.id);
+        return Ok(StatusCode::ACCEPTED);
+    };
+
+    let token = generate_token();
+    let expires_at = Utc::now() + Duration::minutes(RESET_TOKEN_TTL_MINUTES);
+
+    sqlx::query(
+        r#"
+        INSERT INTO password_resets (id, user_id, token_hash, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(user.id)
+    .bind(hash_token(&token))
+    .bind(expires_at)
+    .execute(&state.db)
+    .await?;
+
+    let mail = Mail {
+        to,
+        subject: "GameCall - Reset della password".to_string(),
+        body: format!(
+            "Ciao {},\n\nusa questo codice per reimpostare la password di GameCall:\n\n{}\n\nIl codice scade tra {} minuti. Se non hai richiesto il reset ignora questa email.",
+            user.username, token, RESET_TOKEN_TTL_MINUTES
+        ),
+    };
+
+    if let Err(e) = state.mailer.send(mail).await {
+        tracing::error!("❌ [Mailer] Invio email di reset fallito: {}", e);
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn reset_password(
+    State(state): State<Arc<AppState>>,
+    AppJson(payload): AppJson<ResetPasswordRequest>,
+) -> AppResult<StatusCode> {
+    validate_password(&payload.new_password)?;
+
+    // Consuma il token (monouso) in modo atomico
+    let user_id = sqlx::query_scalar::<_, Uuid>(
+        r#"
+        UPDATE password_resets SET used_at = NOW()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING user_id
+        "#
+    )
+    .bind(hash_token(&payload.token))
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(AppError::InvalidOneTimeToken)?;
+
+    // Invalida gli altri token di reset ancora pendenti
+    sqlx::query(
+        "UPDATE password_resets SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL"
+    )
+    .bind(user_id)
+    .execute(&state.db)
+    .await?;
+
+    set_password(&state, user_id, &payload.new_password, None).await?;
+
+    Ok(StatusCode::OK)
+}
+
+/// Aggiorna la password e revoca tutte le sessioni tranne `keep_session`
+async fn set_password(
+    state: &AppState,
+    user_id: Uuid,
+    new_password: &str,
+    keep_session: Option<Uuid>,
+) -> AppResult<()> {
+    let password_hash = hash_password(new_password)?;
+
+    sqlx::query("UPDATE users SET password_hash = $1, updated_at = NOW() WHERE id = $2")
+        .bind(&password_hash)
+        .bind(user_id)
+        .execute(&state.db)
+        .await?;
+
+    let revoked = sessions::revoke_other_sessions(&state.db, user_id, keep_session)
+        .await?;
+
+    for session_id in revoked {
+        state.ws_state.close_session(&session_id.to_string()).await;
+    }
+
+    Ok(())
+}
+
+/// Indirizzo a cui inviare il codice di reset: solo email verificate
+fn recovery_address(user: &User) -> Option<String> {
+    user.email.clone().filter(|_| user.email_verified)
+}
@@ -0,0 +1,383 @@
+use axum::{
+    extract::State,
+    Extension,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use gamecall_protocol::{Activity, CustomStatus};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::Claims,
+    error::{AppError, AppJson, AppResult},
+    models::User,
+    websocket::WsState,
+};
+
+const MAX_CUSTOM_TEXT_LEN: usize = 128;
+const MAX_EMOJI_LEN: usize = 32;
+const MAX_GAME_NAME_LEN: usize = 128;
+const MAX_PARTY_SIZE: u32 = 100;
+
+/// Stato scelto dall'utente. Chi è `invisible` appare offline a tutti gli altri.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PresenceStatus {
+    Online,
+    Idle,
+    Dnd,
+    Invisible,
+    InCall,
+}
+
+impl PresenceStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Online => "online",
+            Self::Idle => "idle",
+            Self::Dnd => "dnd",
+            Self::Invisible => "invisible",
+            Self::InCall => "in-call",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "online" => Some(Self::Online),
+            "idle" => Some(Self::Idle),
+            "dnd" => Some(Self::Dnd),
+            "invisible" => Some(Self::Invisible),
+            "in-call" => Some(Self::InCall),
+            _ => None,
+        }
+    }
+}
+
+/// Presenza completa di un utente connesso
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Presence {
+    pub status: PresenceStatus,
+    pub custom_status: Option<CustomStatus>,
+    // Attività in corso, non persistita: sparisce alla disconnessione
+    pub activity: Option<Activity>,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self {
+            status: PresenceStatus::Online,
+            custom_status: None,
+            activity: None,
+        }
+    }
+}
+
+impl Presence {
+    /// Come appare agli amici: offline se invisibile, senza stato personalizzato scaduto
+    pub fn view(&self, now: DateTime<Utc>) -> PresenceView {
+        if self.status == PresenceStatus::Invisible {
+            return PresenceView::offline();
+        }
+
+        PresenceView {
+            status: self.status.as_str().to_string(),
+            custom_status: self.custom_status.clone().filter(|custom| custom.is_active(now)),
+            activity: self.activity.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PresenceView {
+    pub status: String,
+    pub custom_status: Option<CustomStatus>,
+    pub activity: Option<Activity>,
+}
+
+impl PresenceView {
+    pub fn offline() -> Self {
+        Self {
+            status: "offline".to_string(),
+            custom_status: None,
+            activity: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ActivityRequest {
+    pub game: String,
+    pub started_at: Option<DateTime<Utc>>,
+    pub party_size: Option<u32>,
+}
+
+/// PUT sostituisce tutta la presenza: i campi omessi vengono azzerati
+#[derive(Debug, Deserialize)]
+pub struct UpdatePresenceRequest {
+    pub status: PresenceStatus,
+    pub custom_status: Option<CustomStatus>,
+    pub activity: Option<ActivityRequest>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PresenceResponse {
+    pub status: PresenceStatus,
+    pub custom_status: Option<CustomStatus>,
+    pub activity: Option<Activity>,
+}
+
+impl From<Presence> for PresenceResponse {
+    fn from(presence: Presence) -> Self {
+        Self {
+            status: presence.status,
+            custom_status: presence.custom_status,
+            activity: presence.activity,
+        }
+    }
+}
+
+pub async fn get_presence(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> AppResult<Json<PresenceResponse>> {
+    let user_id = claims.user_id()?;
+
+    let presence = match state.ws_state.presence_of(&claims.sub).await {
+        Some(presence) => presence,
+        None => load(&state.db, user_id).await?,
+    };
+
+    Ok(Json(presence.into()))
+}
+
+pub async fn update_presence(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    AppJson(payload): AppJson<UpdatePresenceRequest>,
+) -> AppResult<Json<PresenceResponse>> {
+    let user_id = claims.user_id()?;
+    let now = Utc::now();
+
+    let custom_status = payload
+        .custom_status
+        .map(|custom| validate_custom_status(custom, now))
+        .transpose()?
+        .flatten();
+
+    let activity = payload
+        .activity
+        .map(|activity| validate_activity(activity, now))
+        .transpose()?;
+
+    let presence = Presence {
+        status: payload.status,
+        custom_status,
+        activity,
+    };
+
+    save(&state.db, user_id, &presence).await?;
+    state.ws_state.update_presence(&claims.sub, presence.clone()).await;
+
+    match presence.custom_status.as_ref().and_then(|custom| custom.expires_at) {
+        Some(expires_at) => schedule_expiry(state.ws_state.clone(), user_id, expires_at),
+        None => state.ws_state.cancel_custom_status_expiry(user_id),
+    }
+
+    Ok(Json(presence.into()))
+}
+
+/// Presenza salvata (stato e stato personalizzato; l'attività non è persistita)
+pub async fn load(db: &PgPool, user_id: Uuid) -> AppResult<Presence> {
+    let user = sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?
+    .ok_or(AppError::UserNotFound)?;
+
+    Ok(from_user(&user))
+}
+
+fn from_user(user: &User) -> Presence {
+    let custom_status = (user.custom_status_text.is_some() || user.custom_status_emoji.is_some())
+        .then(|| CustomStatus {
+            text: user.custom_status_text.clone(),
+            emoji: user.custom_status_emoji.clone(),
+            expires_at: user.custom_status_expires_at,
+        })
+        .filter(|custom| custom.is_active(Utc::now()));
+
+    Presence {
+        status: PresenceStatus::parse(&user.presence).unwrap_or(PresenceStatus::Online),
+        custom_status,
+        activity: None,
+    }
+}
+
+async fn save(db: &PgPool, user_id: Uuid, presence: &Presence) -> AppResult<()> {
+    let custom = presence.custom_status.as_ref();
+
+    sqlx::query(
+        r#"
+        UPDATE users
+        SET presence = $1, custom_status_text = $2, custom_status_emoji = $3,
+            custom_status_expires_at = $4, updated_at = NOW()
+        WHERE id = $5
+        "#
+    )
+    .bind(presence.status.as_str())
+    .bind(custom.and_then(|c| c.text.clone()))
+    .bind(custom.and_then(|c| c.emoji.clone()))
+    .bind(custom.and_then(|c| c.expires_at))
+    .bind(user_id)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Alla scadenza rimuove lo stato personalizzato e avvisa gli amici
+/// (solo se nel frattempo non è stato sostituito). Un solo task per utente:
+/// una nuova scadenza annulla quella precedente
+pub fn schedule_expiry(ws_state: WsState, user_id: Uuid, expires_at: DateTime<Utc>) {
+    let task_state = ws_state.clone();
+
+    ws_state.spawn_custom_status_expiry(user_id, expires_at, async move {
+        let ws_state = task_state;
+        let delay = (expires_at - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(delay).await;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE users
+            SET custom_status_text = NULL, custom_status_emoji = NULL, custom_status_expires_at = NULL
+            WHERE id = $1 AND custom_status_expires_at = $2
+            "#
+        )
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(ws_state.db())
+        .await;
+
+        match result {
+            Ok(result) if result.rows_affected() > 0 => {
+                ws_state.clear_custom_status(&user_id.to_string(), expires_at).await;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::error!("❌ [Presence] Scadenza stato personalizzato di {} fallita: {}", user_id, e);
+            }
+        }
+
+        ws_state.finish_custom_status_expiry(user_id, expires_at);
+    });
+}
+
+/// Un custom status vuoto (senza testo né emoji) equivale a nessuno
+fn validate_custom_status(custom: CustomStatus, now: DateTime<Utc>) -> AppResult<Option<CustomStatus>> {
+    let text = custom.text.map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+    let emoji = custom.emoji.map(|e| e.trim().to_string()).filter(|e| !e.is_empty());
+
+    if text.as_ref().is_some_and(|t| t.chars().count() > MAX_CUSTOM_TEXT_LEN) {
+        return Err(AppError::Validation(format!("Custom status text must be at most {} characters", MAX_CUSTOM_TEXT_LEN)));
+    }
+
+    if emoji.as_ref().is_some_and(|e| e.chars().count() > MAX_EMOJI_LEN) {
+        return Err(AppError::Validation("Custom status emoji is too long".to_string()));
+    }
+
+    if custom.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(AppError::Validation("Custom status expiry must be in the future".to_string()));
+    }
+
+    if text.is_none() && emoji.is_none() {
+        return Ok(None);
+    }
+
+    Ok(Some(CustomStatus { text, emoji, expires_at: custom.expires_at }))
+}
+
+fn validate_activity(activity: ActivityRequest, now: DateTime<Utc>) -> AppResult<Activity> {
+    let game = activity.game.trim().to_string();
+
+    if game.is_empty() || game.chars().count() > MAX_GAME_NAME_LEN {
+        return Err(AppError::Validation(format!("Game name must be between 1 and {} characters", MAX_GAME_NAME_LEN)));
+    }
+
+    if activity.party_size.is_some_and(|size| size == 0 || size > MAX_PARTY_SIZE) {
+        return Err(AppError::Validation(format!("Party size must be between 1 and {}", MAX_PARTY_SIZE)));
+    }
+
+    Ok(Activity {
+        game,
+        // Non accetta orari nel futuro
+        started_at: activity.started_at.map_or(now, |started_at| started_at.min(now)),
+        party_size: activity.party_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_invisible_appears_offline() {
+        let presence = Presence {
+            status: PresenceStatus::Invisible,
+            custom_status: Some(CustomStatus { text: Some("afk".to_string()), emoji: None, expires_at: None }),
+            activity: None,
+        };
+
+        assert_eq!(presence.view(Utc::now()), PresenceView::offline());
+    }
+
+    #[test]
+    fn test_expired_custom_status_is_hidden() {
+        let now = Utc::now();
+        let presence = Presence {
+            status: PresenceStatus::Dnd,
+            custom_status: Some(CustomStatus {
+                text: Some("in ranked".to_string()),
+                emoji: None,
+                expires_at: Some(now - Duration::minutes(1)),
+            }),
+            activity: None,
+        };
+
+        let view = presence.view(now);
+        assert_eq!(view.status, "dnd");
+        assert_eq!(view.custom_status, None);
+    }
+
+    #[test]
+    fn test_validation() {
+        let now = Utc::now();
+
+        let empty = CustomStatus { text: Some("  ".to_string()), emoji: None, expires_at: None };
+        assert_eq!(validate_custom_status(empty, now).unwrap(), None);
+
+        let expired = CustomStatus { text: Some("ciao".to_string()), emoji: None, expires_at: Some(now) };
+        assert!(validate_custom_status(expired, now).is_err());
+
+        let future_start = ActivityRequest {
+            game: " Rocket League ".to_string(),
+            started_at: Some(now + Duration::hours(1)),
+            party_size: Some(3),
+        };
+        let activity = validate_activity(future_start, now).unwrap();
+        assert_eq!(activity.game, "Rocket League");
+        assert_eq!(activity.started_at, now);
+
+        let no_party = ActivityRequest { game: "Tetris".to_string(), started_at: None, party_size: Some(0) };
+        assert!(validate_activity(no_party, now).is_err());
+
+        assert_eq!(PresenceStatus::parse("in-call"), Some(PresenceStatus::InCall));
+        assert_eq!(serde_json::to_string(&PresenceStatus::InCall).unwrap(), r#""in-call""#);
+    }
+}
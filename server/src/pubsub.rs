@@ -0,0 +1,165 @@
+use async_trait::async_trait;
+use sqlx::{postgres::PgListener, PgPool};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Canale Postgres condiviso da tutte le istanze
+const CHANNEL: &str = "gamecall_ws";
+
+/// NOTIFY accetta al massimo 8000 byte: oltre il messaggio passa dalla tabella ws_pubsub
+const MAX_NOTIFY_BYTES: usize = 7900;
+
+/// Prefisso dei NOTIFY che contengono solo l'id di una riga di ws_pubsub
+const REF_PREFIX: &str = "ref:";
+
+/// Messaggi in attesa per ogni subscriber; oltre i più vecchi vengono scartati
+const CAPACITY: usize = 1024;
+
+/// Canale tra le istanze del server per il fan-out WebSocket. Ogni messaggio
+/// pubblicato arriva a tutti i subscriber, compresa l'istanza che l'ha pubblicato.
+#[async_trait]
+pub trait PubSub: Send + Sync {
+    async fn publish(&self, payload: String);
+    fn subscribe(&self) -> broadcast::Receiver<String>;
+}
+
+/// Singola istanza (o più WsState nello stesso processo, come nei test)
+pub struct MemoryPubSub {
+    tx: broadcast::Sender<String>,
+}
+
+impl MemoryPubSub {
+    pub fn new() -> Self {
+        Self {
+            tx: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl Default for MemoryPubSub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl PubSub for MemoryPubSub {
+    async fn publish(&self, payload: String) {
+        let _ = self.tx.send(payload);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.tx.subscribe()
+    }
+}
+
+/// Più istanze con LISTEN/NOTIFY sul database condiviso. Il listener tiene
+/// occupata una connessione del pool.
+pub struct PgPubSub {
+    db: PgPool,
+    tx: broadcast::Sender<String>,
+}
+
+impl PgPubSub {
+    pub async fn connect(db: PgPool) -> Result<Self, sqlx::Error> {
+        let mut listener = PgListener::connect_with(&db).await?;
+        listener.listen(CHANNEL).await?;
+
+        let tx = broadcast::channel(CAPACITY).0;
+        let forward = tx.clone();
+        let pool = db.clone();
+        tokio::spawn(async move {
+            loop {
+                // recv si riconnette da solo; i NOTIFY inviati durante la disconnessione sono persi
+                let notification = match listener.recv().await {
+                    Ok(notification) => notification,
+                    Err(e) => {
+                        tracing::error!("❌ [PubSub] LISTEN interrotto: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let payload = match notification.payload().strip_prefix(REF_PREFIX) {
+                    Some(id) => match load_large(&pool, id).await {
+                        Ok(Some(payload)) => payload,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            tracing::error!("❌ [PubSub] Lettura messaggio {} fallita: {}", id, e);
+                            continue;
+                        }
+                    },
+                    None => notification.payload().to_string(),
+                };
+                let _ = forward.send(payload);
+            }
+        });
+
+        Ok(Self { db, tx })
+    }
+
+    async fn notify(&self, payload: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CHANNEL)
+            .bind(payload)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    // Salva il messaggio e notifica solo il suo id (le righe servono per pochi secondi)
+    async fn publish_large(&self, payload: &str) -> Result<(), sqlx::Error> {
+        let id = sqlx::query_scalar::<_, i64>("INSERT INTO ws_pubsub (payload) VALUES ($1) RETURNING id")
+            .bind(payload)
+            .fetch_one(&self.db)
+            .await?;
+        self.notify(&format!("{}{}", REF_PREFIX, id)).await?;
+
+        sqlx::query("DELETE FROM ws_pubsub WHERE created_at < NOW() - INTERVAL '1 minute'")
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PubSub for PgPubSub {
+    async fn publish(&self, payload: String) {
+        let result = if payload.len() <= MAX_NOTIFY_BYTES {
+            self.notify(&payload).await
+        } else {
+            self.publish_large(&payload).await
+        };
+
+        if let Err(e) = result {
+            tracing::error!("❌ [PubSub] NOTIFY fallito: {}", e);
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.tx.subscribe()
+    }
+}
+
+async fn load_large(db: &PgPool, id: &str) -> Result<Option<String>, sqlx::Error> {
+    let Ok(id) = id.parse::<i64>() else { return Ok(None) };
+
+    sqlx::query_scalar::<_, String>("SELECT payload FROM ws_pubsub WHERE id = $1")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+}
+
+/// Crea il pub/sub in base a WS_PUBSUB ("postgres" per più istanze, altrimenti "memory")
+pub async fn from_env(db: &PgPool) -> Result<Arc<dyn PubSub>, sqlx::Error> {
+    let backend = std::env::var("WS_PUBSUB").unwrap_or_else(|_| "memory".to_string());
+
+    if backend == "postgres" {
+        tracing::info!("📡 [PubSub] LISTEN/NOTIFY su {}", CHANNEL);
+        Ok(Arc::new(PgPubSub::connect(db.clone()).await?))
+    } else {
+        tracing::info!("📡 [PubSub] In memoria (singola istanza)");
+        Ok(Arc::new(MemoryPubSub::new()))
+    }
+}
@@ -0,0 +1,455 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+
+use crate::{auth::Claims, error::AppError, utils::client_ip};
+
+/// Dimensione massima del body letto per estrarre lo username
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Token bucket: `capacity` richieste di burst, poi `refill_per_sec` al secondo
+#[derive(Debug, Clone, Copy)]
+pub struct BucketPolicy {
+    pub capacity: f64,
+    pub refill_per_sec: f64,
+}
+
+/// Lockout progressivo: dopo `threshold` fallimenti l'account è bloccato per
+/// `base`, raddoppiando a ogni ulteriore fallimento fino a `max`
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    pub failure_status: StatusCode,
+    pub threshold: u32,
+    pub base: Duration,
+    pub max: Duration,
+    /// Dopo questo intervallo senza fallimenti il contatore riparte da zero
+    pub reset_after: Duration,
+}
+
+impl LockoutPolicy {
+    fn lockout_for(&self, failures: u32) -> Option<Duration> {
+        if failures < self.threshold {
+            return None;
+        }
+        let exponent = (failures - self.threshold).min(16);
+        Some(self.base.saturating_mul(1 << exponent).min(self.max))
+    }
+}
+
+/// Come identificare l'account a cui appartiene una richiesta
+#[derive(Debug, Clone, Copy)]
+pub enum AccountKey {
+    /// Campo "username" del body JSON (route pubbliche come il login)
+    Username,
+    /// Utente autenticato dal middleware JWT
+    Authenticated,
+    /// Utente (`sub`) del campo "mfa_token" del body (secondo passaggio del login)
+    MfaToken,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitRule {
+    /// Prefisso delle chiavi, separa i bucket delle diverse route
+    pub name: &'static str,
+    pub per_ip: Option<BucketPolicy>,
+    pub per_account: Option<(AccountKey, BucketPolicy)>,
+    /// Richiede `per_account`
+    pub lockout: Option<LockoutPolicy>,
+}
+
+impl RateLimitRule {
+    /// Login: 10/min per IP, 5/min per account, lockout dopo 5 password errate
+    pub fn login() -> Self {
+        Self {
+            name: "login",
+            per_ip: Some(BucketPolicy { capacity: 10.0, refill_per_sec: 10.0 / 60.0 }),
+            per_account: Some((AccountKey::Username, BucketPolicy { capacity: 5.0, refill_per_sec: 5.0 / 60.0 })),
+            lockout: Some(LockoutPolicy {
+                failure_status: StatusCode::UNAUTHORIZED,
+                threshold: 5,
+                base: Duration::from_secs(60),
+                max: Duration::from_secs(60 * 60),
+                reset_after: Duration::from_secs(15 * 60),
+            }),
+        }
+    }
+
+    /// Secondo passaggio 2FA: 10/min per IP, 5/min per utente, così un codice TOTP non si
+    /// può indovinare cambiando IP o chiedendo nuovi mfa_token con una password rubata
+    pub fn login_mfa() -> Self {
+        Self {
+            name: "login_mfa",
+            per_ip: Some(BucketPolicy { capacity: 10.0, refill_per_sec: 10.0 / 60.0 }),
+            per_account: Some((AccountKey::MfaToken, BucketPolicy { capacity: 5.0, refill_per_sec: 5.0 / 60.0 })),
+            lockout: None,
+        }
+    }
+
+    /// Limite solo per IP (registrazione, refresh, reset e cambio password, verifica email)
+    pub fn per_ip(name: &'static str, per_minute: f64) -> Self {
+        Self {
+            name,
+            per_ip: Some(BucketPolicy { capacity: per_minute, refill_per_sec: per_minute / 60.0 }),
+            per_account: None,
+            lockout: None,
+        }
+    }
+
+    /// Ricerca per friend code: limita l'enumerazione dei codici
+    pub fn friend_lookup() -> Self {
+        Self {
+            name: "friend_lookup",
+            per_ip: Some(BucketPolicy { capacity: 30.0, refill_per_sec: 30.0 / 60.0 }),
+            per_account: Some((AccountKey::Authenticated, BucketPolicy { capacity: 10.0, refill_per_sec: 10.0 / 60.0 })),
+            lockout: Some(LockoutPolicy {
+                failure_status: StatusCode::NOT_FOUND,
+                threshold: 10,
+                base: Duration::from_secs(60),
+                max: Duration::from_secs(30 * 60),
+                reset_after: Duration::from_secs(15 * 60),
+            }),
+        }
+    }
+
+    /// Aggiornamenti di presenza (arrivano a tutti gli amici): burst di 10, poi 10/min per account
+    pub fn presence() -> Self {
+        Self {
+            name: "presence",
+            per_ip: None,
+            per_account: Some((AccountKey::Authenticated, BucketPolicy { capacity: 10.0, refill_per_sec: 10.0 / 60.0 })),
+            lockout: None,
+        }
+    }
+
+    /// Invio di messaggi diretti: burst di 20, poi 1 al secondo per account
+    pub fn messages() -> Self {
+        Self {
+            name: "messages",
+            per_ip: None,
+            per_account: Some((AccountKey::Authenticated, BucketPolicy { capacity: 20.0, refill_per_sec: 1.0 })),
+            lockout: None,
+        }
+    }
+}
+
+/// Storage dei bucket e dei lockout. In memoria per singola istanza,
+/// implementabile su Postgres per condividere i limiti tra più istanze.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Consuma un token; se il bucket è vuoto restituisce il tempo di attesa
+    async fn take(&self, key: &str, policy: &BucketPolicy) -> Result<(), Duration>;
+    /// Tempo rimanente di lockout, se la chiave è bloccata
+    async fn locked_for(&self, key: &str) -> Option<Duration>;
+    /// Registra un fallimento e restituisce l'eventuale lockout applicato
+    async fn record_failure(&self, key: &str, policy: &LockoutPolicy) -> Option<Duration>;
+    async fn clear_failures(&self, key: &str);
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl MemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rimuove le voci inattive (da chiamare periodicamente)
+    pub fn prune(&self, idle: Duration) {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| now.duration_since(bucket.updated_at) < idle);
+        self.failures.lock().unwrap().retain(|_, failures| {
+            now.duration_since(failures.last_failure) < idle
+                || failures.locked_until.is_some_and(|until| until > now)
+        });
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn take(&self, key: &str, policy: &BucketPolicy) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: policy.capacity,
+            updated_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * policy.refill_per_sec).min(policy.capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / policy.refill_per_sec))
+        }
+    }
+
+    async fn locked_for(&self, key: &str) -> Option<Duration> {
+        let now = Instant::now();
+        self.failures
+            .lock()
+            .unwrap()
+            .get(key)
+            .and_then(|failures| failures.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    async fn record_failure(&self, key: &str, policy: &LockoutPolicy) -> Option<Duration> {
+        let now = Instant::now();
+        let mut all = self.failures.lock().unwrap();
+        let failures = all.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+
+        if now.duration_since(failures.last_failure) > policy.reset_after {
+            failures.count = 0;
+        }
+
+        failures.count += 1;
+        failures.last_failure = now;
+
+        let lockout = policy.lockout_for(failures.count);
+        failures.locked_until = lockout.map(|duration| now + duration);
+        lockout
+    }
+
+    async fn clear_failures(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+}
+
+/// Layer tower che applica una `RateLimitRule` alle route su cui è montato
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    store: Arc<dyn RateLimitStore>,
+    rule: Arc<RateLimitRule>,
+    trust_proxy_headers: bool,
+}
+
+impl RateLimitLayer {
+    pub fn new(store: Arc<dyn RateLimitStore>, rule: RateLimitRule, trust_proxy_headers: bool) -> Self {
+        Self {
+            store,
+            rule: Arc::new(rule),
+            trust_proxy_headers,
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            store: self.store.clone(),
+            rule: self.rule.clone(),
+            trust_proxy_headers: self.trust_proxy_headers,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    store: Arc<dyn RateLimitStore>,
+    rule: Arc<RateLimitRule>,
+    trust_proxy_headers: bool,
+}
+
+impl<S> Service<Request> for RateLimitService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // Usa il servizio già pronto e lascia un clone al suo posto
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let store = self.store.clone();
+        let rule = self.rule.clone();
+        let trust_proxy_headers = self.trust_proxy_headers;
+
+        Box::pin(async move {
+            let (req, account) = match account_key(req, &rule).await {
+                Ok(extracted) => extracted,
+                Err(response) => return Ok(response),
+            };
+
+            // Bucket per IP
+            if let Some(policy) = &rule.per_ip {
+                let ip = req
+                    .extensions()
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(addr)| client_ip(req.headers(), *addr, trust_proxy_headers));
+
+                if let Some(ip) = ip {
+                    let key = format!("{}:ip:{}", rule.name, ip);
+                    if let Err(retry_after) = store.take(&key, policy).await {
+                        return Ok(AppError::RateLimited { retry_after }.into_response());
+                    }
+                }
+            }
+
+            // Lockout e bucket per account
+            let account_key = account.map(|account| format!("{}:account:{}", rule.name, account));
+            if let (Some(key), Some((_, policy))) = (&account_key, &rule.per_account) {
+                if let Some(retry_after) = store.locked_for(key).await {
+                    return Ok(AppError::RateLimited { retry_after }.into_response());
+                }
+                if let Err(retry_after) = store.take(key, policy).await {
+                    return Ok(AppError::RateLimited { retry_after }.into_response());
+                }
+            }
+
+            let response = inner.call(req).await?;
+
+            if let (Some(key), Some(lockout)) = (&account_key, &rule.lockout) {
+                if response.status() == lockout.failure_status {
+                    if let Some(duration) = store.record_failure(key, lockout).await {
+                        tracing::warn!("🔒 [RateLimit] {} bloccato per {}s", key, duration.as_secs());
+                    }
+                } else if response.status().is_success() {
+                    store.clear_failures(key).await;
+                }
+            }
+
+            Ok(response)
+        })
+    }
+}
+
+/// Estrae la chiave account secondo la regola. Per `Username` e `MfaToken` il body
+/// viene letto e poi ricostruito, così l'handler lo riceve intatto.
+async fn account_key(req: Request, rule: &RateLimitRule) -> Result<(Request, Option<String>), Response> {
+    let field = match rule.per_account {
+        None => return Ok((req, None)),
+        Some((AccountKey::Authenticated, _)) => {
+            let user_id = req.extensions().get::<Claims>().map(|claims| claims.sub.clone());
+            return Ok((req, user_id));
+        }
+        Some((AccountKey::Username, _)) => "username",
+        Some((AccountKey::MfaToken, _)) => "mfa_token",
+    };
+
+    let (parts, body) = req.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .map_err(|_| AppError::PayloadTooLarge.into_response())?;
+
+    let value = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .ok()
+        .and_then(|json| json.get(field)?.as_str().map(str::to_string));
+
+    let account = match rule.per_account {
+        Some((AccountKey::MfaToken, _)) => value.as_deref().and_then(jwt_subject),
+        _ => value,
+    };
+
+    Ok((Request::from_parts(parts, Body::from(bytes)), account))
+}
+
+/// `sub` di un JWT senza verificarne la firma: serve solo come chiave del bucket,
+/// il token viene validato dall'handler
+fn jwt_subject(token: &str) -> Option<String> {
+    let payload = token.split('.').nth(1)?;
+    let bytes = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    let claims = serde_json::from_slice::<serde_json::Value>(&bytes).ok()?;
+    claims.get("sub")?.as_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_bucket_exhausts_and_reports_retry_after() {
+        let store = MemoryRateLimitStore::new();
+        let policy = BucketPolicy { capacity: 3.0, refill_per_sec: 0.5 };
+
+        for _ in 0..3 {
+            assert!(store.take("k", &policy).await.is_ok());
+        }
+
+        let retry_after = store.take("k", &policy).await.unwrap_err();
+        assert!(retry_after <= Duration::from_secs(2));
+        assert!(retry_after > Duration::from_millis(1500));
+
+        // Chiavi diverse hanno bucket indipendenti
+        assert!(store.take("altro", &policy).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_progressive_lockout() {
+        let store = MemoryRateLimitStore::new();
+        let policy = LockoutPolicy {
+            failure_status: StatusCode::UNAUTHORIZED,
+            threshold: 3,
+            base: Duration::from_secs(60),
+            max: Duration::from_secs(200),
+            reset_after: Duration::from_secs(600),
+        };
+
+        assert_eq!(store.record_failure("k", &policy).await, None);
+        assert_eq!(store.record_failure("k", &policy).await, None);
+        assert!(store.locked_for("k").await.is_none());
+
+        assert_eq!(store.record_failure("k", &policy).await, Some(Duration::from_secs(60)));
+        assert_eq!(store.record_failure("k", &policy).await, Some(Duration::from_secs(120)));
+        assert_eq!(store.record_failure("k", &policy).await, Some(Duration::from_secs(200)));
+        assert!(store.locked_for("k").await.is_some());
+
+        store.clear_failures("k").await;
+        assert!(store.locked_for("k").await.is_none());
+    }
+
+    #[test]
+    fn test_jwt_subject() {
+        let payload = URL_SAFE_NO_PAD.encode(r#"{"sub":"user-1","purpose":"mfa"}"#);
+        assert_eq!(jwt_subject(&format!("header.{}.firma", payload)), Some("user-1".to_string()));
+        assert_eq!(jwt_subject("non-un-jwt"), None);
+    }
+}
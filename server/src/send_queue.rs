@@ -0,0 +1,136 @@
+use gamecall_protocol::ServerMessage;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Notify};
+
+/// Frame in attesa per connessione (replay del resume compreso)
+const RELIABLE_CAPACITY: usize = 256;
+
+/// Aggiornamenti di presenza in attesa per connessione; oltre vengono scartati
+const PRESENCE_CAPACITY: usize = 64;
+
+/// Coda di un frame in uscita
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lane {
+    // Presenza: si può perdere se il client è lento, ne arriveranno di nuovi
+    Presence,
+    // Tutto il resto (segnali WebRTC, chiamate, amicizie, risposte): mai scartato
+    Reliable,
+}
+
+impl Lane {
+    pub fn of(message: &ServerMessage) -> Self {
+        match message {
+            ServerMessage::UserOnline { .. }
+            | ServerMessage::UserOffline { .. }
+            | ServerMessage::PresenceUpdate { .. } => Self::Presence,
+            _ => Self::Reliable,
+        }
+    }
+}
+
+/// Contatori delle code di invio, esposti su /metrics
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    pub presence_dropped: AtomicU64,
+    pub slow_consumer_closed: AtomicU64,
+}
+
+/// Lato di invio della coda di una connessione
+#[derive(Clone)]
+pub struct Outbound {
+    reliable: mpsc::Sender<String>,
+    presence: mpsc::Sender<String>,
+    overflow: Arc<Notify>,
+    metrics: Arc<QueueMetrics>,
+}
+
+/// Lato di ricezione, usato dal task che scrive sul socket
+pub struct Inbound {
+    pub reliable: mpsc::Receiver<String>,
+    pub presence: mpsc::Receiver<String>,
+    // Segnalato quando la coda affidabile è piena: il client va disconnesso
+    pub overflow: Arc<Notify>,
+}
+
+pub fn channel(metrics: Arc<QueueMetrics>) -> (Outbound, Inbound) {
+    let (reliable_tx, reliable_rx) = mpsc::channel(RELIABLE_CAPACITY);
+    let (presence_tx, presence_rx) = mpsc::channel(PRESENCE_CAPACITY);
+    let overflow = Arc::new(Notify::new());
+
+    let outbound = Outbound {
+        reliable: reliable_tx,
+        presence: presence_tx,
+        overflow: overflow.clone(),
+        metrics,
+    };
+    let inbound = Inbound {
+        reliable: reliable_rx,
+        presence: presence_rx,
+        overflow,
+    };
+    (outbound, inbound)
+}
+
+impl Outbound {
+    /// Accoda senza attendere. Se la coda affidabile è piena il client non riesce a
+    /// stare al passo: la connessione viene chiusa e gli eventi durevoli arriveranno
+    /// con il resume.
+    pub fn send(&self, frame: String, lane: Lane) {
+        match lane {
+            Lane::Presence => {
+                if let Err(mpsc::error::TrySendError::Full(_)) = self.presence.try_send(frame) {
+                    self.metrics.presence_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Lane::Reliable => {
+                if let Err(mpsc::error::TrySendError::Full(_)) = self.reliable.try_send(frame) {
+                    self.overflow.notify_one();
+                }
+            }
+        }
+    }
+
+    /// Frame in attesa di essere scritti sul socket
+    pub fn depth(&self) -> usize {
+        (self.reliable.max_capacity() - self.reliable.capacity())
+            + (self.presence.max_capacity() - self.presence.capacity())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_presence_dropped_and_reliable_overflow() {
+        let metrics = Arc::new(QueueMetrics::default());
+        let (outbound, mut inbound) = channel(metrics.clone());
+
+        for _ in 0..PRESENCE_CAPACITY + 5 {
+            outbound.send("presence".to_string(), Lane::Presence);
+        }
+        assert_eq!(metrics.presence_dropped.load(Ordering::Relaxed), 5);
+        assert_eq!(outbound.depth(), PRESENCE_CAPACITY);
+
+        for _ in 0..RELIABLE_CAPACITY {
+            outbound.send("signal".to_string(), Lane::Reliable);
+        }
+        assert_eq!(outbound.depth(), PRESENCE_CAPACITY + RELIABLE_CAPACITY);
+
+        // Nessun frame affidabile scartato finché c'è spazio; poi la connessione va chiusa
+        outbound.send("signal".to_string(), Lane::Reliable);
+        tokio::time::timeout(std::time::Duration::from_secs(1), inbound.overflow.notified())
+            .await
+            .unwrap();
+        assert_eq!(inbound.reliable.recv().await.unwrap(), "signal");
+    }
+
+    #[test]
+    fn test_lanes() {
+        assert_eq!(Lane::of(&ServerMessage::UserOffline { user_id: "a".to_string() }), Lane::Presence);
+        assert_eq!(Lane::of(&ServerMessage::CallTimeout { call_id: "c".to_string() }), Lane::Reliable);
+    }
+}
@@ -0,0 +1,283 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    Extension,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{AppState, auth::Claims, error::{AppError, AppResult}, models::Session, utils::{client_ip, generate_token, hash_token}};
+
+/// Durata di un refresh token (e quindi della sessione se non viene mai rinnovata)
+pub const REFRESH_TOKEN_TTL_DAYS: i64 = 30;
+
+/// Informazioni sul dispositivo che ha effettuato il login
+#[derive(Debug, Default)]
+pub struct DeviceInfo {
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+impl DeviceInfo {
+    pub fn from_request(
+        headers: &HeaderMap,
+        addr: SocketAddr,
+        trust_proxy_headers: bool,
+        device_name: Option<String>,
+    ) -> Self {
+        Self {
+            device_name: device_name
+                .map(|name| name.trim().chars().take(100).collect::<String>())
+                .filter(|name| !name.is_empty()),
+            user_agent: headers
+                .get(header::USER_AGENT)
+                .and_then(|h| h.to_str().ok())
+                .map(|ua| ua.chars().take(512).collect()),
+            ip_address: Some(client_ip(headers, addr, trust_proxy_headers)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    pub id: String,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub current: bool,
+}
+
+/// Esito della rotazione di un refresh token
+pub enum RotateOutcome {
+    /// Token valido: restituisce l'utente e il nuovo refresh token
+    Rotated { session_id: Uuid, user_id: Uuid, refresh_token: String },
+    /// Token già usato: la sessione è stata revocata (i suoi WebSocket vanno chiusi)
+    Reused { session_id: Uuid },
+    /// Sessione inesistente, scaduta o revocata
+    Invalid,
+}
+
+/// Crea una nuova sessione e restituisce (session_id, refresh_token)
+pub async fn create_session(db: &PgPool, user_id: Uuid, device: DeviceInfo) -> Result<(Uuid, String), sqlx::Error> {
+    let session_id = Uuid::new_v4();
+    let secret = generate_token();
+    let expires_at = Utc::now() + Duration::days(REFRESH_TOKEN_TTL_DAYS);
+
+    // Una sessione nuova parte dall'ultimo evento dell'utente: non deve ricevere (né
+    // trattenere nell'outbox) gli eventi precedenti al login
+    sqlx::query(
+        r#"
+        INSERT INTO sessions (id, user_id, refresh_token_hash, device_name, user_agent, ip_address, expires_at, ws_acked_seq)
+        SELECT $1, $2, $3, $4, $5, $6, $7, outbox_seq FROM users WHERE id = $2
+        "#
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .bind(hash_token(&secret))
+    .bind(device.device_name)
+    .bind(device.user_agent)
+    .bind(device.ip_address)
+    .bind(expires_at)
+    .execute(db)
+    .await?;
+
+    Ok((session_id, format_refresh_token(session_id, &secret)))
+}
+
+/// Ruota un refresh token. Se viene presentato un token già sostituito,
+/// l'intera sessione viene revocata (possibile furto del token).
+pub async fn rotate_refresh_token(db: &PgPool, refresh_token: &str) -> Result<RotateOutcome, sqlx::Error> {
+    let Some((session_id, secret)) = parse_refresh_token(refresh_token) else {
+        return Ok(RotateOutcome::Invalid);
+    };
+
+    let new_secret = generate_token();
+
+    // Sostituzione atomica: riesce solo se il token presentato è quello corrente
+    let rotated = sqlx::query_scalar::<_, Uuid>(
+        r#"
+        UPDATE sessions
+        SET refresh_token_hash = $3, last_seen_at = NOW(),
+            expires_at = NOW() + make_interval(days => $4)
+        WHERE id = $1 AND refresh_token_hash = $2
+          AND revoked_at IS NULL AND expires_at > NOW()
+        RETURNING user_id
+        "#
+    )
+    .bind(session_id)
+    .bind(hash_token(secret))
+    .bind(hash_token(&new_secret))
+    .bind(REFRESH_TOKEN_TTL_DAYS as i32)
+    .fetch_optional(db)
+    .await?;
+
+    if let Some(user_id) = rotated {
+        return Ok(RotateOutcome::Rotated {
+            session_id,
+            user_id,
+            refresh_token: format_refresh_token(session_id, &new_secret),
+        });
+    }
+
+    // Sessione ancora attiva ma hash diverso: il token è stato riutilizzato
+    let revoked = sqlx::query(
+        "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL AND expires_at > NOW()"
+    )
+    .bind(session_id)
+    .execute(db)
+    .await?;
+
+    if revoked.rows_affected() > 0 {
+        tracing::warn!("⚠️ [Auth] Riutilizzo refresh token per sessione {}, sessione revocata", session_id);
+        Ok(RotateOutcome::Reused { session_id })
+    } else {
+        Ok(RotateOutcome::Invalid)
+    }
+}
+
+/// Revoca tutte le sessioni dell'utente tranne quella indicata e restituisce gli id revocati
+pub async fn revoke_other_sessions(db: &PgPool, user_id: Uuid, keep: Option<Uuid>) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar::<_, Uuid>(
+        r#"
+        UPDATE sessions SET revoked_at = NOW()
+        WHERE user_id = $1 AND revoked_at IS NULL AND ($2::uuid IS NULL OR id <> $2)
+        RETURNING id
+        "#
+    )
+    .bind(user_id)
+    .bind(keep)
+    .fetch_all(db)
+    .await
+}
+
+/// Revoca una sessione dell'utente. Restituisce false se non esisteva o era già revocata.
+pub async fn revoke_session(db: &PgPool, user_id: Uuid, session_id: Uuid) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL"
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Verifica che la sessione esista, non sia scaduta né revocata, e aggiorna
+/// last_seen_at (al massimo una volta al minuto per non scrivere a ogni richiesta)
+pub async fn is_session_active(db: &PgPool, session_id: Uuid) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>(
+        r#"
+        WITH active AS (
+            SELECT id FROM sessions
+            WHERE id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+        ), touched AS (
+            UPDATE sessions SET last_seen_at = NOW()
+            WHERE id IN (SELECT id FROM active) AND last_seen_at < NOW() - INTERVAL '1 minute'
+        )
+        SELECT EXISTS(SELECT 1 FROM active)
+        "#
+    )
+    .bind(session_id)
+    .fetch_one(db)
+    .await
+}
+
+pub async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> AppResult<Json<Vec<SessionResponse>>> {
+    let user_id = claims.user_id()?;
+
+    let sessions = sqlx::query_as::<_, Session>(
+        r#"
+        SELECT * FROM sessions
+        WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+        ORDER BY last_seen_at DESC
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(&state.db)
+    .await?
+    .into_iter()
+    .map(|session| SessionResponse {
+        current: session.id.to_string() == claims.sid,
+        id: session.id.to_string(),
+        device_name: session.device_name,
+        user_agent: session.user_agent,
+        ip_address: session.ip_address,
+        created_at: session.created_at,
+        last_seen_at: session.last_seen_at,
+    })
+    .collect();
+
+    Ok(Json(sessions))
+}
+
+pub async fn delete_session(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    Path(session_id): Path<String>,
+) -> AppResult<StatusCode> {
+    let user_id = claims.user_id()?;
+
+    let session_id = Uuid::parse_str(&session_id)
+        .map_err(|_| AppError::InvalidId("session ID"))?;
+
+    let revoked = revoke_session(&state.db, user_id, session_id)
+        .await?;
+
+    if !revoked {
+        return Err(AppError::SessionNotFound);
+    }
+
+    // Chiudi subito i WebSocket aperti con questa sessione
+    state.ws_state.close_session(&session_id.to_string()).await;
+
+    Ok(StatusCode::OK)
+}
+
+/// Il refresh token ha il formato "<session_id>.<secret>"
+fn format_refresh_token(session_id: Uuid, secret: &str) -> String {
+    format!("{}.{}", session_id, secret)
+}
+
+fn parse_refresh_token(token: &str) -> Option<(Uuid, &str)> {
+    let (session_id, secret) = token.split_once('.')?;
+    let session_id = Uuid::parse_str(session_id).ok()?;
+    if secret.is_empty() {
+        return None;
+    }
+    Some((session_id, secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refresh_token_roundtrip() {
+        let session_id = Uuid::new_v4();
+        let secret = generate_token();
+        let token = format_refresh_token(session_id, &secret);
+
+        let (parsed_id, parsed_secret) = parse_refresh_token(&token).unwrap();
+        assert_eq!(parsed_id, session_id);
+        assert_eq!(parsed_secret, secret);
+    }
+
+    #[test]
+    fn test_parse_invalid_refresh_token() {
+        assert!(parse_refresh_token("not-a-token").is_none());
+        assert!(parse_refresh_token("abc.def").is_none());
+        assert!(parse_refresh_token(&format!("{}.", Uuid::new_v4())).is_none());
+    }
+}
@@ -0,0 +1,147 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+
+/// Durata di un passo TOTP in secondi (RFC 6238)
+pub const STEP_SECONDS: u64 = 30;
+
+/// Cifre dei codici generati dalle app authenticator
+pub const DIGITS: u32 = 6;
+
+/// Passi di tolleranza prima/dopo quello corrente (sfasamento orologi)
+const ALLOWED_DRIFT: i64 = 1;
+
+const ISSUER: &str = "GameCall";
+
+/// Genera un nuovo segreto TOTP (160 bit, come raccomandato da RFC 4226)
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// Codifica base32 (senza padding) usata nelle app authenticator
+pub fn encode_secret(secret: &[u8]) -> String {
+    base32::encode(base32::Alphabet::Rfc4648 { padding: false }, secret)
+}
+
+pub fn decode_secret(encoded: &str) -> Option<Vec<u8>> {
+    base32::decode(base32::Alphabet::Rfc4648 { padding: false }, encoded)
+}
+
+/// Codice HOTP (RFC 4226) per un contatore
+fn hotp(secret: &[u8], counter: u64, digits: u32) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = ((hash[offset] as u32 & 0x7f) << 24)
+        | ((hash[offset + 1] as u32) << 16)
+        | ((hash[offset + 2] as u32) << 8)
+        | (hash[offset + 3] as u32);
+
+    format!("{:0width$}", binary % 10u32.pow(digits), width = digits as usize)
+}
+
+/// Codice TOTP valido all'istante `unix_time`
+pub fn code_at(secret: &[u8], unix_time: u64, digits: u32) -> String {
+    hotp(secret, unix_time / STEP_SECONDS, digits)
+}
+
+/// Verifica un codice e restituisce il passo a cui corrisponde, così il chiamante
+/// può rifiutare i codici già usati (passo <= ultimo passo accettato)
+pub fn verify(secret: &[u8], code: &str, unix_time: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = (unix_time / STEP_SECONDS) as i64;
+    (-ALLOWED_DRIFT..=ALLOWED_DRIFT)
+        .map(|drift| current + drift)
+        .filter(|step| *step >= 0)
+        .find(|step| {
+            let expected = code_at(secret, *step as u64 * STEP_SECONDS, DIGITS);
+            constant_time_eq(expected.as_bytes(), code.as_bytes())
+        })
+        .map(|step| step as u64)
+}
+
+/// URI otpauth:// da mostrare come QR code nelle app authenticator
+pub fn provisioning_uri(encoded_secret: &str, username: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = ISSUER,
+        account = percent_encode(username),
+        secret = encoded_secret,
+        digits = DIGITS,
+        period = STEP_SECONDS,
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Segreto SHA1 dei test vector di RFC 6238 (Appendice B)
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        let vectors = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+
+        for (time, expected) in vectors {
+            assert_eq!(code_at(RFC_SECRET, time, 8), expected, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn test_verify_with_drift() {
+        let now = 1111111111;
+        let code = code_at(RFC_SECRET, now, DIGITS);
+        let step = now / STEP_SECONDS;
+
+        assert_eq!(verify(RFC_SECRET, &code, now), Some(step));
+        assert_eq!(verify(RFC_SECRET, &code, now + STEP_SECONDS), Some(step));
+        assert_eq!(verify(RFC_SECRET, &code, now + 3 * STEP_SECONDS), None);
+        assert_eq!(verify(RFC_SECRET, "12345", now), None);
+        assert_eq!(verify(RFC_SECRET, "abcdef", now), None);
+    }
+
+    #[test]
+    fn test_secret_roundtrip_and_uri() {
+        let secret = generate_secret();
+        let encoded = encode_secret(&secret);
+        assert_eq!(decode_secret(&encoded), Some(secret));
+
+        let uri = provisioning_uri(&encoded, "mario rossi");
+        assert!(uri.starts_with("otpauth://totp/GameCall:mario%20rossi?secret="));
+        assert!(uri.contains("issuer=GameCall"));
+    }
+}
@@ -1,12 +1,15 @@
-use rand::Rng;
+use axum::http::HeaderMap;
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+
+// Caratteri disponibili: maiuscole e numeri (esclusi I, O, 0, 1 per evitare confusione)
+const CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
 
 /// Genera un friend code univoco nel formato "GC-XXXX-XXXX"
 pub fn generate_friend_code() -> String {
     let mut rng = rand::thread_rng();
     
-    // Caratteri disponibili: maiuscole e numeri (esclusi I, O, 0, 1 per evitare confusione)
-    const CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
-    
     let part1: String = (0..4)
         .map(|_| {
             let idx = rng.gen_range(0..CHARS.len());
@@ -24,6 +27,67 @@ pub fn generate_friend_code() -> String {
     format!("GC-{}-{}", part1, part2)
 }
 
+/// Genera un codice di recupero 2FA nel formato "XXXXX-XXXXX"
+pub fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut part = || -> String {
+        (0..5)
+            .map(|_| CHARS[rng.gen_range(0..CHARS.len())] as char)
+            .collect()
+    };
+
+    let part1 = part();
+    let part2 = part();
+    format!("{}-{}", part1, part2)
+}
+
+/// Genera un token opaco casuale (256 bit, hex)
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Hash sha256 (hex) di un token, da salvare nel database al posto del token in chiaro
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Ricava l'IP del client. Gli header del proxy sono impostabili da chiunque, quindi si
+/// usano solo con `trust_proxy_headers` (dietro il proxy di Fly.io l'indirizzo della
+/// connessione è quello del proxy); altrimenti vale l'indirizzo della connessione.
+pub fn client_ip(headers: &HeaderMap, addr: SocketAddr, trust_proxy_headers: bool) -> String {
+    if !trust_proxy_headers {
+        return addr.ip().to_string();
+    }
+
+    headers
+        .get("fly-client-ip")
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| {
+            // L'ultimo indirizzo è quello aggiunto dal proxy; i precedenti arrivano dal client
+            headers
+                .get("x-forwarded-for")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|v| v.rsplit(',').next())
+        })
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty())
+        .unwrap_or_else(|| addr.ip().to_string())
+}
+
+/// TRUST_PROXY_HEADERS=true solo se il server è raggiungibile unicamente dal proxy
+pub fn trust_proxy_headers_from_env() -> bool {
+    std::env::var("TRUST_PROXY_HEADERS").is_ok_and(|value| value == "true")
+}
+
+/// Violazione di un vincolo UNIQUE (SQLSTATE 23505)
+pub fn is_unique_violation(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .and_then(|db| db.code())
+        .is_some_and(|code| code == "23505")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -31,7 +95,7 @@ mod tests {
     #[test]
     fn test_generate_friend_code() {
         let code = generate_friend_code();
-        assert_eq!(code.len(), 11); // GC-XXXX-XXXX
+        assert_eq!(code.len(), 12); // GC-XXXX-XXXX
         assert!(code.starts_with("GC-"));
         
         // Verifica formato
@@ -52,4 +116,28 @@ mod tests {
         // Dovrebbero essere tutti univoci
         assert_eq!(codes.len(), 1000);
     }
+
+    #[test]
+    fn test_generate_recovery_code() {
+        let code = generate_recovery_code();
+        assert_eq!(code.len(), 11); // XXXXX-XXXXX
+        assert_eq!(code.chars().nth(5), Some('-'));
+        assert!(code.chars().filter(|c| *c != '-').all(|c| CHARS.contains(&(c as u8))));
+    }
+
+    #[test]
+    fn test_client_ip_prefers_proxy_headers() {
+        let addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        assert_eq!(client_ip(&HeaderMap::new(), addr, true), "10.0.0.1");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "192.0.2.99, 203.0.113.7".parse().unwrap());
+        assert_eq!(client_ip(&headers, addr, true), "203.0.113.7");
+
+        headers.insert("fly-client-ip", "198.51.100.2".parse().unwrap());
+        assert_eq!(client_ip(&headers, addr, true), "198.51.100.2");
+
+        // Senza proxy fidato gli header vengono ignorati
+        assert_eq!(client_ip(&headers, addr, false), "10.0.0.1");
+    }
 }
@@ -1,123 +1,1180 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
         State,
     },
     response::Response,
     Extension,
 };
-use futures::{sink::SinkExt, stream::StreamExt};
+use chrono::{DateTime, Utc};
+use futures::{
+    sink::SinkExt,
+    stream::{SplitSink, SplitStream, StreamExt},
+};
+use gamecall_protocol::{
+    ClientMessage, ErrorCode, ServerMessage, CAPABILITIES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::{broadcast, RwLock};
+use uuid::Uuid;
+
+use crate::{
+    calls::{self, CallRegistry},
+    error::{AppError, AppResult},
+    friends,
+    outbox,
+    presence::{self, Presence, PresenceView},
+    pubsub::{MemoryPubSub, PubSub},
+    rate_limit::{BucketPolicy, MemoryRateLimitStore, RateLimitStore},
+    send_queue::{self, Lane, Outbound, QueueMetrics},
+    ws_ticket::WsIdentity,
+};
+
+/// Close code inviato quando la sessione del socket viene revocata
+const CLOSE_SESSION_REVOKED: u16 = 4001;
+
+/// Close code inviato quando il client non risponde ai ping
+const CLOSE_HEARTBEAT_TIMEOUT: u16 = 4002;
+
+/// Close code inviato quando il client è troppo lento e la sua coda di invio è piena
+const CLOSE_SLOW_CONSUMER: u16 = 4003;
+
+/// Close code inviato quando il primo frame non è un `hello` valido
+const CLOSE_HANDSHAKE_FAILED: u16 = 4004;
+
+/// Tempo concesso al client per inviare `hello` dopo l'apertura del socket
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Close code standard "Service Restart", inviato durante lo shutdown del server
+const CLOSE_SERVICE_RESTART: u16 = 1012;
+
+/// Attesa suggerita ai client prima di riconnettersi dopo uno shutdown (con jitter)
+const RECONNECT_HINT_MS: std::ops::Range<u32> = 1000..5000;
+
+/// Dimensione massima di un frame ricevuto dal client
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
 
-use crate::auth::Claims;
+/// Dimensione massima di un segnale WebRTC inoltrato (SDP con molti candidati)
+const MAX_SIGNAL_BYTES: usize = 32 * 1024;
 
-// Tipi di messaggi WebSocket
+/// Eventi rinviati al massimo in un resume; oltre il client deve risincronizzarsi via REST
+const MAX_REPLAY: usize = 50;
+
+/// Segnali WebRTC per connessione: burst per lo scambio ICE iniziale, poi 10 al secondo
+const SIGNAL_RATE: BucketPolicy = BucketPolicy {
+    capacity: 50.0,
+    refill_per_sec: 10.0,
+};
+
+/// Ogni nodo annuncia di essere attivo con questa frequenza
+const NODE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Un nodo silenzioso da più di così è considerato morto (i suoi utenti vanno offline)
+const NODE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Attesa delle risposte degli altri nodi all'avvio, prima di correggere la presenza nel database
+const SYNC_GRACE: Duration = Duration::from_secs(5);
+
+/// Ping WebSocket inviati dal server: una connessione che non risponde per
+/// `max_missed` intervalli consecutivi viene chiusa (es. TCP half-open)
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(20),
+            max_missed: 3,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    /// WS_PING_INTERVAL_SECS e WS_MAX_MISSED_PONGS (default 20 secondi e 3 ping)
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let interval = std::env::var("WS_PING_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|secs| *secs > 0)
+            .map_or(default.interval, Duration::from_secs);
+        let max_missed = std::env::var("WS_MAX_MISSED_PONGS")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .filter(|missed| *missed > 0)
+            .unwrap_or(default.max_missed);
+
+        Self { interval, max_missed }
+    }
+
+    /// Oltre questo silenzio dall'ultimo pong la connessione è considerata morta
+    fn timeout(&self) -> Duration {
+        self.interval * self.max_missed
+    }
+}
+
+// Connessioni di un utente (una per dispositivo), per connection id
+type UserConnections = HashMap<Uuid, Outbound>;
+
+// Scadenza pendente dello stato personalizzato e il task che la applica
+type CustomStatusExpiry = (DateTime<Utc>, tokio::task::AbortHandle);
+
+/// Eventi scambiati tra i nodi tramite il pub/sub
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-pub enum WsMessage {
-    #[serde(rename = "friend_added")]
-    FriendAdded {
-        friend_id: String,
-        friend_username: String,
-        friend_code: String,
-    },
-    #[serde(rename = "friend_removed")]
-    FriendRemoved {
-        friend_id: String,
-    },
-    #[serde(rename = "user_online")]
-    UserOnline {
-        user_id: String,
-    },
-    #[serde(rename = "user_offline")]
-    UserOffline {
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ClusterEvent {
+    // Frame per i dispositivi dell'utente connessi agli altri nodi
+    Deliver { user_id: String, payload: String, lane: Lane },
+    // Prima e ultima connessione dell'utente sul nodo che invia l'evento
+    Connected { user_id: String, presence: Presence },
+    // `at`: istante della disconnessione sul nodo d'origine, per ordinare le scritture
+    Disconnected {
         user_id: String,
+        #[serde(default = "Utc::now")]
+        at: DateTime<Utc>,
     },
-    #[serde(rename = "webrtc_signal")]
-    WebRTCSignal {
-        from_user_id: String,
-        to_user_id: String,
-        signal: serde_json::Value,
-    },
-    #[serde(rename = "ping")]
-    Ping,
-    #[serde(rename = "pong")]
-    Pong,
+    PresenceChanged { user_id: String, presence: Presence },
+    InvalidateFriends { user_ids: Vec<String> },
+    CloseSession { session_id: String },
+    // Messaggio call_* per una chiamata gestita da un altro nodo
+    CallMessage { user_id: String, message: ClientMessage },
+    // Un nodo appena avviato chiede agli altri i loro utenti connessi
+    SyncRequest,
+    Heartbeat,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    node_id: Uuid,
+    event: ClusterEvent,
 }
 
-// Stato globale per gestire le connessioni WebSocket
+// Presenza di un utente connesso e nodi su cui ha almeno una connessione
+struct ClusterPresence {
+    presence: Presence,
+    nodes: HashSet<Uuid>,
+}
+
+// Modifica al registro di presenza del cluster
+enum PresenceChange {
+    Connected(Uuid, Presence),
+    Disconnected(Uuid),
+    Updated(Presence),
+}
+
+impl PresenceChange {
+    // Applica la modifica e restituisce la presenza prima e dopo (None = offline)
+    fn apply(
+        self,
+        presences: &mut HashMap<String, ClusterPresence>,
+        user_id: &str,
+    ) -> (Option<Presence>, Option<Presence>) {
+        let before = presences.get(user_id).map(|entry| entry.presence.clone());
+
+        match self {
+            // Se l'utente è già connesso altrove mantiene la presenza corrente
+            Self::Connected(node_id, presence) => {
+                presences
+                    .entry(user_id.to_string())
+                    .or_insert_with(|| ClusterPresence { presence, nodes: HashSet::new() })
+                    .nodes
+                    .insert(node_id);
+            }
+            Self::Disconnected(node_id) => {
+                if let Some(entry) = presences.get_mut(user_id) {
+                    entry.nodes.remove(&node_id);
+                    if entry.nodes.is_empty() {
+                        presences.remove(user_id);
+                    }
+                }
+            }
+            // Non connesso: appare offline, non c'è niente da aggiornare
+            Self::Updated(presence) => {
+                if let Some(entry) = presences.get_mut(user_id) {
+                    entry.presence = presence;
+                }
+            }
+        }
+
+        (before, presences.get(user_id).map(|entry| entry.presence.clone()))
+    }
+}
+
+// Stato globale per gestire le connessioni WebSocket. Con più istanze ogni nodo ha le
+// proprie connessioni e riceve dagli altri (via pub/sub) i messaggi e la presenza.
 #[derive(Clone)]
 pub struct WsState {
-    // Mappa user_id -> broadcast sender
-    pub connections: Arc<RwLock<HashMap<String, broadcast::Sender<String>>>>,
+    node_id: Uuid,
+    // Mappa user_id -> connessioni aperte su questo nodo
+    connections: Arc<RwLock<HashMap<String, UserConnections>>>,
+    // Mappa session_id -> segnale di chiusura per i socket di quella sessione
+    session_closers: Arc<RwLock<HashMap<String, broadcast::Sender<()>>>>,
+    // Cache user_id -> amici accettati, invalidata quando cambia un'amicizia
+    friend_cache: Arc<RwLock<HashMap<String, Arc<HashSet<String>>>>>,
+    // Presenza degli utenti connessi a qualsiasi nodo (l'utente è online finché ne ha uno)
+    presences: Arc<RwLock<HashMap<String, ClusterPresence>>>,
+    // Ultimo evento ricevuto da ciascun altro nodo
+    nodes: Arc<Mutex<HashMap<Uuid, Instant>>>,
+    pubsub: Arc<dyn PubSub>,
+    queue_metrics: Arc<QueueMetrics>,
+    // Shutdown in corso: niente nuovi socket, quelli aperti vengono chiusi
+    draining: Arc<AtomicBool>,
+    shutdown_tx: broadcast::Sender<()>,
+    // Socket aperti su questo nodo, fino alla fine del loro cleanup
+    live_sockets: Arc<AtomicUsize>,
+    calls: CallRegistry,
+    // Al massimo un task di scadenza dello stato personalizzato per utente
+    custom_status_expiries: Arc<Mutex<HashMap<Uuid, CustomStatusExpiry>>>,
+    heartbeat: HeartbeatConfig,
+    db: PgPool,
 }
 
 impl WsState {
-    pub fn new() -> Self {
+    pub fn new(db: PgPool) -> Self {
         Self {
+            node_id: Uuid::new_v4(),
             connections: Arc::new(RwLock::new(HashMap::new())),
+            session_closers: Arc::new(RwLock::new(HashMap::new())),
+            friend_cache: Arc::new(RwLock::new(HashMap::new())),
+            presences: Arc::new(RwLock::new(HashMap::new())),
+            nodes: Arc::new(Mutex::new(HashMap::new())),
+            pubsub: Arc::new(MemoryPubSub::new()),
+            queue_metrics: Arc::new(QueueMetrics::default()),
+            draining: Arc::new(AtomicBool::new(false)),
+            shutdown_tx: broadcast::channel(1).0,
+            live_sockets: Arc::new(AtomicUsize::new(0)),
+            calls: CallRegistry::default(),
+            custom_status_expiries: Arc::new(Mutex::new(HashMap::new())),
+            heartbeat: HeartbeatConfig::default(),
+            db,
+        }
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: HeartbeatConfig) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    pub fn with_pubsub(mut self, pubsub: Arc<dyn PubSub>) -> Self {
+        self.pubsub = pubsub;
+        self
+    }
+
+    pub fn db(&self) -> &PgPool {
+        &self.db
+    }
+
+    // Avvia la scadenza dello stato personalizzato, annullando quella pendente dell'utente.
+    // Lo spawn avviene con la mappa bloccata, così il task non può togliersi prima di esserci
+    pub fn spawn_custom_status_expiry<F>(&self, user_id: Uuid, expires_at: DateTime<Utc>, task: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let mut expiries = self.custom_status_expiries.lock().unwrap();
+        let handle = tokio::spawn(task).abort_handle();
+
+        if let Some((_, previous)) = expiries.insert(user_id, (expires_at, handle)) {
+            previous.abort();
         }
     }
 
-    // Invia messaggio a un utente specifico
-    pub async fn send_to_user(&self, user_id: &str, message: &WsMessage) {
+    // Stato personalizzato sostituito da uno senza scadenza (o rimosso)
+    pub fn cancel_custom_status_expiry(&self, user_id: Uuid) {
+        if let Some((_, previous)) = self.custom_status_expiries.lock().unwrap().remove(&user_id) {
+            previous.abort();
+        }
+    }
+
+    // Chiamato dal task di scadenza quando ha finito (solo se è ancora quello registrato)
+    pub fn finish_custom_status_expiry(&self, user_id: Uuid, expires_at: DateTime<Utc>) {
+        let mut expiries = self.custom_status_expiries.lock().unwrap();
+
+        if expiries.get(&user_id).is_some_and(|(pending, _)| *pending == expires_at) {
+            expiries.remove(&user_id);
+        }
+    }
+
+    #[cfg(test)]
+    fn pending_custom_status_expiries(&self) -> usize {
+        self.custom_status_expiries.lock().unwrap().len()
+    }
+
+    pub fn calls(&self) -> &CallRegistry {
+        &self.calls
+    }
+
+    pub fn node_id(&self) -> Uuid {
+        self.node_id
+    }
+
+    /// Avvia la ricezione degli eventi degli altri nodi e l'heartbeat di questo nodo;
+    /// dopo la sincronizzazione iniziale segna offline gli utenti non connessi a nessun nodo
+    /// e libera i partecipanti delle chiamate di nodi non più attivi
+    pub fn start(&self) {
+        let state = self.clone();
+        let mut events = self.pubsub.subscribe();
+        tokio::spawn(async move {
+            state.publish(ClusterEvent::SyncRequest).await;
+
+            let mut heartbeat = tokio::time::interval(NODE_HEARTBEAT_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = heartbeat.tick() => {
+                        state.publish(ClusterEvent::Heartbeat).await;
+                        state.sweep_nodes().await;
+                    }
+                    payload = events.recv() => match payload {
+                        Ok(payload) => state.handle_cluster_event(&payload).await,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            tracing::error!("❌ [Cluster] {} eventi persi", skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            }
+        });
+
+        let state = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(SYNC_GRACE).await;
+
+            let online: Vec<Uuid> = state
+                .presences
+                .read()
+                .await
+                .keys()
+                .filter_map(|user_id| Uuid::parse_str(user_id).ok())
+                .collect();
+            match reconcile_presence(&state.db, &online).await {
+                Ok(0) => {}
+                Ok(stale) => tracing::info!("🧹 [Cluster] {} utenti rimasti online segnati offline", stale),
+                Err(e) => tracing::error!("❌ [Cluster] Correzione presenza fallita: {}", e),
+            }
+
+            match calls::release_stale(&state.db, &state.live_nodes()).await {
+                Ok(0) => {}
+                Ok(stale) => tracing::info!("🧹 [Cluster] {} partecipanti di chiamate chiuse liberati", stale),
+                Err(e) => tracing::error!("❌ [Cluster] Pulizia partecipanti delle chiamate fallita: {}", e),
+            }
+        });
+    }
+
+    async fn publish(&self, event: ClusterEvent) {
+        let envelope = Envelope { node_id: self.node_id, event };
+        self.pubsub.publish(serde_json::to_string(&envelope).unwrap()).await;
+    }
+
+    // Evento pubblicato da un altro nodo
+    async fn handle_cluster_event(&self, payload: &str) {
+        let Ok(Envelope { node_id, event }) = serde_json::from_str::<Envelope>(payload) else {
+            tracing::error!("❌ [Cluster] Evento non valido ricevuto");
+            return;
+        };
+
+        // Gli eventi di questo nodo sono già stati applicati prima di pubblicarli
+        if node_id == self.node_id {
+            return;
+        }
+        self.nodes.lock().unwrap().insert(node_id, Instant::now());
+
+        match event {
+            ClusterEvent::Deliver { user_id, payload, lane } => self.deliver_local(&user_id, &payload, lane).await,
+            ClusterEvent::Connected { user_id, presence } => {
+                self.change_presence(&user_id, PresenceChange::Connected(node_id, presence)).await;
+            }
+            ClusterEvent::Disconnected { user_id, at } => {
+                if self.change_presence(&user_id, PresenceChange::Disconnected(node_id)).await {
+                    self.went_offline(&user_id, at).await;
+                }
+            }
+            ClusterEvent::PresenceChanged { user_id, presence } => {
+                self.change_presence(&user_id, PresenceChange::Updated(presence)).await;
+            }
+            ClusterEvent::InvalidateFriends { user_ids } => {
+                let mut cache = self.friend_cache.write().await;
+                for user_id in user_ids {
+                    cache.remove(&user_id);
+                }
+            }
+            ClusterEvent::CloseSession { session_id } => self.close_local_session(&session_id).await,
+            ClusterEvent::CallMessage { user_id, message } => {
+                calls::handle_forwarded(self, &user_id, message).await;
+            }
+            ClusterEvent::SyncRequest => {
+                let local_users: Vec<String> = self.connections.read().await.keys().cloned().collect();
+                for user_id in local_users {
+                    if let Some(presence) = self.presence_of(&user_id).await {
+                        self.publish(ClusterEvent::Connected { user_id, presence }).await;
+                    }
+                }
+            }
+            ClusterEvent::Heartbeat => {}
+        }
+    }
+
+    // Nodi che non inviano più heartbeat (es. crash): i loro utenti risultano disconnessi
+    async fn sweep_nodes(&self) {
+        let dead: Vec<Uuid> = {
+            let mut nodes = self.nodes.lock().unwrap();
+            let dead: Vec<Uuid> = nodes
+                .iter()
+                .filter(|(_, last_seen)| last_seen.elapsed() > NODE_TIMEOUT)
+                .map(|(node_id, _)| *node_id)
+                .collect();
+            for node_id in &dead {
+                nodes.remove(node_id);
+            }
+            dead
+        };
+        let any_dead = !dead.is_empty();
+
+        for node_id in dead {
+            tracing::info!("💀 [Cluster] Nodo {} non risponde, i suoi utenti vanno offline", node_id);
+            let users: Vec<String> = self
+                .presences
+                .read()
+                .await
+                .iter()
+                .filter(|(_, entry)| entry.nodes.contains(&node_id))
+                .map(|(user_id, _)| user_id.clone())
+                .collect();
+
+            for user_id in users {
+                if self.change_presence(&user_id, PresenceChange::Disconnected(node_id)).await {
+                    self.went_offline(&user_id, Utc::now()).await;
+                }
+            }
+        }
+
+        if any_dead {
+            if let Err(e) = calls::release_stale(&self.db, &self.live_nodes()).await {
+                tracing::error!("❌ [Cluster] Pulizia partecipanti delle chiamate fallita: {}", e);
+            }
+        }
+    }
+
+    // Questo nodo e quelli che hanno inviato eventi di recente
+    fn live_nodes(&self) -> Vec<Uuid> {
+        let mut live: Vec<Uuid> = self.nodes.lock().unwrap().keys().copied().collect();
+        live.push(self.node_id);
+        live
+    }
+
+    /// Shutdown del nodo: rifiuta nuovi socket, invia `server_restarting` ai client e
+    /// attende la chiusura dei socket; gli utenti ancora connessi allo scadere del
+    /// timeout vengono segnati offline subito
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.draining.store(true, Ordering::SeqCst);
+        let _ = self.shutdown_tx.send(());
+
+        let deadline = Instant::now() + timeout;
+        while self.live_sockets.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let remaining: Vec<String> = self.connections.write().await.drain().map(|(user_id, _)| user_id).collect();
+        if !remaining.is_empty() {
+            tracing::info!("⏱️ [WebSocket] {} utenti ancora connessi allo shutdown, segnati offline", remaining.len());
+        }
+        for user_id in remaining {
+            let at = Utc::now();
+            let (before, after) = {
+                let mut presences = self.presences.write().await;
+                PresenceChange::Disconnected(self.node_id).apply(&mut presences, &user_id)
+            };
+            self.publish(ClusterEvent::Disconnected { user_id: user_id.clone(), at })
+                .await;
+            if self.presence_changed(&user_id, before, after).await {
+                self.went_offline(&user_id, at).await;
+            }
+        }
+    }
+
+    // Chiude tutti i socket aperti con una sessione (es. sessione revocata), su ogni nodo
+    pub async fn close_session(&self, session_id: &str) {
+        self.close_local_session(session_id).await;
+        self.publish(ClusterEvent::CloseSession { session_id: session_id.to_string() })
+            .await;
+    }
+
+    async fn close_local_session(&self, session_id: &str) {
+        let closers = self.session_closers.read().await;
+        if let Some(tx) = closers.get(session_id) {
+            let _ = tx.send(());
+        }
+    }
+
+    // Invia messaggio a un utente specifico (su tutti i suoi dispositivi, anche su altri nodi)
+    // (gli eventi durevoli vengono prima salvati nell'outbox, anche se l'utente è offline)
+    pub async fn send_to_user(&self, user_id: &str, message: &ServerMessage) {
+        let json = if message.is_durable() {
+            self.persist_event(user_id, message).await
+        } else {
+            serde_json::to_string(message).unwrap()
+        };
+
+        let lane = Lane::of(message);
+        self.deliver_local(user_id, &json, lane).await;
+
+        if self.is_connected_elsewhere(user_id).await {
+            self.publish(ClusterEvent::Deliver {
+                user_id: user_id.to_string(),
+                payload: json,
+                lane,
+            })
+            .await;
+        }
+    }
+
+    async fn deliver_local(&self, user_id: &str, json: &str, lane: Lane) {
         let connections = self.connections.read().await;
-        if let Some(tx) = connections.get(user_id) {
-            let json = serde_json::to_string(message).unwrap();
-            let _ = tx.send(json);
+        if let Some(user_connections) = connections.get(user_id) {
+            for tx in user_connections.values() {
+                tx.send(json.to_string(), lane);
+            }
+        }
+    }
+
+    async fn is_connected_elsewhere(&self, user_id: &str) -> bool {
+        self.presences
+            .read()
+            .await
+            .get(user_id)
+            .is_some_and(|entry| entry.nodes.iter().any(|node_id| *node_id != self.node_id))
+    }
+
+    // Un messaggio call_* per una chiamata gestita da un altro nodo
+    pub(crate) async fn forward_call_message(&self, user_id: &str, message: ClientMessage) {
+        self.publish(ClusterEvent::CallMessage {
+            user_id: user_id.to_string(),
+            message,
+        })
+        .await;
+    }
+
+    // Salva l'evento nell'outbox e restituisce il JSON con il suo seq
+    async fn persist_event(&self, user_id: &str, message: &ServerMessage) -> String {
+        let json = serde_json::to_string(message).unwrap();
+        let Ok(id) = Uuid::parse_str(user_id) else { return json };
+
+        match outbox::append(&self.db, id, message).await {
+            Ok(seq) => outbox::with_seq(&json, seq),
+            Err(e) => {
+                tracing::error!("❌ [WebSocket] Salvataggio evento per {} fallito: {}", user_id, e);
+                json
+            }
         }
     }
 
-    // Broadcast a tutti gli utenti online
-    pub async fn broadcast(&self, message: &WsMessage) {
+    // Eventi da rinviare a una connessione che riprende, seguiti da `resumed`
+    async fn replay(&self, user_id: &str, session_id: &str, last_seq: Option<i64>) -> Result<Vec<String>, sqlx::Error> {
+        let (Ok(user_id), Ok(session_id)) = (Uuid::parse_str(user_id), Uuid::parse_str(session_id)) else {
+            return Ok(Vec::new());
+        };
+
+        let last_seq = match last_seq {
+            Some(seq) => seq,
+            None => outbox::acked_seq(&self.db, session_id).await?,
+        };
+        let current = outbox::current_seq(&self.db, user_id).await?;
+        let events = outbox::since(&self.db, user_id, last_seq).await?;
+
+        // Eventi già eliminati dall'outbox o troppi da rinviare
+        let complete = events.first().map_or(current <= last_seq, |(seq, _)| *seq == last_seq + 1);
+        let reset = !complete || events.len() > MAX_REPLAY;
+
+        let mut frames: Vec<String> = if reset {
+            Vec::new()
+        } else {
+            events.iter().map(|(seq, payload)| outbox::with_seq(payload, *seq)).collect()
+        };
+        frames.push(serde_json::to_string(&ServerMessage::Resumed { last_seq: current, reset }).unwrap());
+        Ok(frames)
+    }
+
+    // Registra una connessione; se è la prima dell'utente su questo nodo ne annuncia la
+    // presenza agli altri nodi e restituisce l'istante del cambio (per persist_presence)
+    async fn register(
+        &self,
+        user_id: &str,
+        connection_id: Uuid,
+        tx: Outbound,
+        presence: Presence,
+    ) -> Option<DateTime<Utc>> {
+        // Il registro viene aggiornato sotto il lock delle connessioni, così una
+        // disconnessione contemporanea non può sovrascrivere questa connessione
+        let (at, before, after) = {
+            let mut connections = self.connections.write().await;
+            let at = Utc::now();
+            let user_connections = connections.entry(user_id.to_string()).or_default();
+            user_connections.insert(connection_id, tx);
+            let devices = user_connections.len();
+            tracing::info!(
+                "✅ [WebSocket] Utente {} connesso ({} dispositivi, {} utenti su questo nodo)",
+                user_id,
+                devices,
+                connections.len()
+            );
+
+            if devices > 1 {
+                return None;
+            }
+            let mut presences = self.presences.write().await;
+            let (before, after) = PresenceChange::Connected(self.node_id, presence.clone()).apply(&mut presences, user_id);
+            (at, before, after)
+        };
+
+        self.publish(ClusterEvent::Connected {
+            user_id: user_id.to_string(),
+            presence,
+        })
+        .await;
+        self.presence_changed(user_id, before, after).await;
+        Some(at)
+    }
+
+    // Rimuove una connessione; se l'utente non è più connesso a nessun nodo restituisce
+    // l'istante della disconnessione
+    async fn unregister(&self, user_id: &str, connection_id: Uuid) -> Option<DateTime<Utc>> {
+        let (at, before, after) = {
+            let mut connections = self.connections.write().await;
+            let user_connections = connections.get_mut(user_id)?;
+            user_connections.remove(&connection_id);
+            if !user_connections.is_empty() {
+                return None;
+            }
+            connections.remove(user_id);
+            let at = Utc::now();
+
+            let mut presences = self.presences.write().await;
+            let (before, after) = PresenceChange::Disconnected(self.node_id).apply(&mut presences, user_id);
+            (at, before, after)
+        };
+
+        self.publish(ClusterEvent::Disconnected { user_id: user_id.to_string(), at })
+            .await;
+        self.presence_changed(user_id, before, after).await.then_some(at)
+    }
+
+    // Applica una modifica al registro di presenza; true se l'utente è andato offline
+    async fn change_presence(&self, user_id: &str, change: PresenceChange) -> bool {
+        let (before, after) = {
+            let mut presences = self.presences.write().await;
+            change.apply(&mut presences, user_id)
+        };
+        self.presence_changed(user_id, before, after).await
+    }
+
+    // Ogni nodo avvisa gli amici connessi a sé stesso; true se l'utente è andato offline
+    async fn presence_changed(&self, user_id: &str, before: Option<Presence>, after: Option<Presence>) -> bool {
+        let now = Utc::now();
+        let view = |presence: &Option<Presence>| {
+            presence
+                .as_ref()
+                .map_or_else(PresenceView::offline, |presence| presence.view(now))
+        };
+        self.notify_presence(user_id, &view(&before), view(&after)).await;
+
+        before.is_some() && after.is_none()
+    }
+
+    // Utente disconnesso da tutti i nodi: chiude le chiamate gestite da questo nodo e
+    // salva l'ultimo accesso (lo fanno tutti i nodi, la scrittura è idempotente)
+    async fn went_offline(&self, user_id: &str, at: DateTime<Utc>) {
+        calls::drop_user(self, user_id).await;
+        self.persist_presence(user_id, false, at).await;
+
+        // La lista amici viene ricaricata quando serve di nuovo
+        self.friend_cache.write().await.remove(user_id);
+    }
+
+    // Connesso ad almeno un nodo
+    pub async fn is_online(&self, user_id: &str) -> bool {
+        self.presences.read().await.contains_key(user_id)
+    }
+
+    // Presenza completa di un utente connesso (None se offline)
+    pub async fn presence_of(&self, user_id: &str) -> Option<Presence> {
+        self.presences
+            .read()
+            .await
+            .get(user_id)
+            .map(|entry| entry.presence.clone())
+    }
+
+    // Presenza come la vedono gli amici (offline se disconnesso o invisibile)
+    pub async fn visible_presence(&self, user_id: &str) -> PresenceView {
+        self.presences
+            .read()
+            .await
+            .get(user_id)
+            .map_or_else(PresenceView::offline, |entry| entry.presence.view(Utc::now()))
+    }
+
+    // Aggiorna la presenza di un utente connesso e avvisa gli amici se cambia ciò che vedono
+    pub async fn update_presence(&self, user_id: &str, presence: Presence) {
+        // Non connesso: appare offline, non c'è niente da notificare
+        if !self.is_online(user_id).await {
+            return;
+        }
+
+        self.change_presence(user_id, PresenceChange::Updated(presence.clone())).await;
+        self.publish(ClusterEvent::PresenceChanged {
+            user_id: user_id.to_string(),
+            presence,
+        })
+        .await;
+    }
+
+    // Stato personalizzato scaduto (se nel frattempo non è stato cambiato)
+    pub async fn clear_custom_status(&self, user_id: &str, expires_at: DateTime<Utc>) {
+        let Some(mut presence) = self.presence_of(user_id).await else { return };
+
+        if presence.custom_status.as_ref().and_then(|custom| custom.expires_at) == Some(expires_at) {
+            presence.custom_status = None;
+            self.update_presence(user_id, presence).await;
+        }
+    }
+
+    // Invia agli amici il cambio di presenza visibile; user_online/user_offline
+    // solo quando l'utente compare o sparisce (connessione o modalità invisibile)
+    async fn notify_presence(&self, user_id: &str, before: &PresenceView, after: PresenceView) {
+        if *before == after {
+            return;
+        }
+
+        let was_visible = before.status != "offline";
+        let is_visible = after.status != "offline";
+        if !was_visible && is_visible {
+            self.broadcast_presence(user_id, &ServerMessage::UserOnline { user_id: user_id.to_string() })
+                .await;
+        } else if was_visible && !is_visible {
+            self.broadcast_presence(user_id, &ServerMessage::UserOffline { user_id: user_id.to_string() })
+                .await;
+        }
+
+        self.broadcast_presence(
+            user_id,
+            &ServerMessage::PresenceUpdate {
+                user_id: user_id.to_string(),
+                status: after.status,
+                custom_status: after.custom_status,
+                activity: after.activity,
+            },
+        )
+        .await;
+    }
+
+    // Amici accettati di un utente (dalla cache, altrimenti dal database)
+    pub(crate) async fn friends_of(&self, user_id: &str) -> Arc<HashSet<String>> {
+        if let Some(friends) = self.friend_cache.read().await.get(user_id) {
+            return friends.clone();
+        }
+
+        let Ok(id) = Uuid::parse_str(user_id) else {
+            return Arc::default();
+        };
+
+        match friends::accepted_friend_ids(&self.db, id).await {
+            Ok(ids) => {
+                let friends: Arc<HashSet<String>> = Arc::new(ids.iter().map(Uuid::to_string).collect());
+                self.friend_cache
+                    .write()
+                    .await
+                    .insert(user_id.to_string(), friends.clone());
+                friends
+            }
+            Err(e) => {
+                // Senza la lista amici non si sa a chi mostrare la presenza: nessuno
+                tracing::error!("❌ [WebSocket] Lettura amici fallita: {}", e);
+                Arc::default()
+            }
+        }
+    }
+
+    // Salva la presenza nel database (users.status e, all'uscita, last_seen_at). `at` è
+    // l'istante del cambio preso sotto il lock delle connessioni: una scrittura più
+    // vecchia di quella già salvata (es. l'uscita di un socket chiuso che arriva dopo
+    // la riconnessione) viene ignorata
+    async fn persist_presence(&self, user_id: &str, online: bool, at: DateTime<Utc>) {
+        let Ok(id) = Uuid::parse_str(user_id) else { return };
+
+        let query = if online {
+            r#"
+            UPDATE users SET status = 'online', presence_changed_at = $2
+            WHERE id = $1 AND (presence_changed_at IS NULL OR presence_changed_at <= $2)
+            "#
+        } else {
+            r#"
+            UPDATE users SET status = 'offline', last_seen_at = $2, presence_changed_at = $2
+            WHERE id = $1 AND (presence_changed_at IS NULL OR presence_changed_at < $2)
+            "#
+        };
+
+        if let Err(e) = sqlx::query(query).bind(id).bind(at).execute(&self.db).await {
+            tracing::error!("❌ [WebSocket] Salvataggio presenza di {} fallito: {}", user_id, e);
+        }
+    }
+
+    // Da chiamare quando cambiano le amicizie di questi utenti (vale per tutti i nodi)
+    pub async fn invalidate_friends(&self, user_ids: &[Uuid]) {
+        let user_ids: Vec<String> = user_ids.iter().map(Uuid::to_string).collect();
+        {
+            let mut cache = self.friend_cache.write().await;
+            for user_id in &user_ids {
+                cache.remove(user_id);
+            }
+        }
+        self.publish(ClusterEvent::InvalidateFriends { user_ids }).await;
+    }
+
+    // Nuova amicizia: ognuno dei due vede subito la presenza dell'altro
+    pub async fn exchange_presence(&self, a: &str, b: &str) {
+        for (user_id, friend_id) in [(a, b), (b, a)] {
+            let view = self.visible_presence(user_id).await;
+            if view.status == "offline" {
+                continue;
+            }
+
+            self.send_to_user(friend_id, &ServerMessage::UserOnline { user_id: user_id.to_string() })
+                .await;
+            self.send_to_user(
+                friend_id,
+                &ServerMessage::PresenceUpdate {
+                    user_id: user_id.to_string(),
+                    status: view.status,
+                    custom_status: view.custom_status,
+                    activity: view.activity,
+                },
+            )
+            .await;
+        }
+    }
+
+    // Notifica online/offline solo agli amici connessi a questo nodo
+    async fn broadcast_presence(&self, user_id: &str, message: &ServerMessage) {
+        let friends = self.friends_of(user_id).await;
         let connections = self.connections.read().await;
         let json = serde_json::to_string(message).unwrap();
-        for tx in connections.values() {
-            let _ = tx.send(json.clone());
+        for friend_id in friends.iter() {
+            for tx in connections.get(friend_id).into_iter().flat_map(|c| c.values()) {
+                tx.send(json.clone(), Lane::Presence);
+            }
+        }
+    }
+
+    // Un segnale WebRTC passa solo se nessuno dei due utenti ha bloccato l'altro
+    async fn can_relay(&self, from_user_id: &str, to_user_id: &str) -> bool {
+        // Bloccare elimina l'amicizia, quindi basta la cache degli amici accettati
+        self.friends_of(from_user_id).await.contains(to_user_id)
+    }
+
+    /// Controlla un segnale WebRTC prima dell'inoltro; in caso di rifiuto
+    /// restituisce il messaggio di errore per il mittente. Conta la presenza vista dagli
+    /// amici: un destinatario invisibile risulta offline come uno disconnesso
+    async fn check_relay(
+        &self,
+        from_user_id: &str,
+        to_user_id: &str,
+        signal: &serde_json::Value,
+        limiter: &MemoryRateLimitStore,
+    ) -> Result<(), ServerMessage> {
+        if limiter.take("webrtc_signal", &SIGNAL_RATE).await.is_err() {
+            return Err(ServerMessage::error(ErrorCode::RateLimited, "Too many signals, slow down"));
+        }
+
+        if signal.to_string().len() > MAX_SIGNAL_BYTES {
+            return Err(ServerMessage::error(ErrorCode::PayloadTooLarge, "Signal payload is too large"));
+        }
+
+        if !self.can_relay(from_user_id, to_user_id).await {
+            return Err(ServerMessage::error(ErrorCode::NotFriends, "Signals can only be sent to accepted friends"));
         }
+
+        if self.visible_presence(to_user_id).await.status == "offline" {
+            return Err(ServerMessage::error(ErrorCode::RecipientOffline, "Recipient is offline"));
+        }
+
+        Ok(())
     }
 }
 
+/// Metriche WebSocket di questo nodo in formato Prometheus (GET /metrics, solo sulla
+/// porta interna METRICS_PORT)
+pub async fn metrics(State(ws_state): State<WsState>) -> String {
+    let (connections, depth, max_depth) = {
+        let connections = ws_state.connections.read().await;
+        let depths: Vec<usize> = connections.values().flat_map(|c| c.values()).map(Outbound::depth).collect();
+        (depths.len(), depths.iter().sum::<usize>(), depths.iter().copied().max().unwrap_or(0))
+    };
+    let metrics = &ws_state.queue_metrics;
+
+    format!(
+        "# TYPE ws_connections gauge\n\
+         ws_connections {}\n\
+         # TYPE ws_send_queue_depth gauge\n\
+         ws_send_queue_depth {}\n\
+         # TYPE ws_send_queue_max_depth gauge\n\
+         ws_send_queue_max_depth {}\n\
+         # TYPE ws_presence_dropped_total counter\n\
+         ws_presence_dropped_total {}\n\
+         # TYPE ws_slow_consumer_closed_total counter\n\
+         ws_slow_consumer_closed_total {}\n",
+        connections,
+        depth,
+        max_depth,
+        metrics.presence_dropped.load(Ordering::Relaxed),
+        metrics.slow_consumer_closed.load(Ordering::Relaxed),
+    )
+}
+
+/// Gli utenti rimasti "online" nel database (es. dopo un crash) ma non connessi a
+/// nessun nodo vengono segnati offline
+async fn reconcile_presence(db: &PgPool, online: &[Uuid]) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE users SET status = 'offline', last_seen_at = NOW(), presence_changed_at = NOW()
+        WHERE status <> 'offline' AND NOT (id = ANY($1))
+        "#
+    )
+    .bind(online)
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
 // Handler WebSocket
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     State(ws_state): State<WsState>,
-    Extension(claims): Extension<Claims>,
-) -> Response {
-    let user_id = claims.sub.clone();
+    Extension(identity): Extension<WsIdentity>,
+) -> AppResult<Response> {
+    // Durante lo shutdown il client deve riconnettersi a un altro nodo (o dopo il riavvio)
+    if ws_state.draining.load(Ordering::SeqCst) {
+        return Err(AppError::ShuttingDown);
+    }
+
+    let WsIdentity { user_id, session_id } = identity;
+
+    Ok(ws
+        .max_message_size(MAX_MESSAGE_BYTES)
+        .on_upgrade(move |socket| handle_socket(socket, user_id, session_id, ws_state)))
+}
+
+// Tiene il conto dei socket aperti; rilasciato a fine cleanup
+struct LiveSocket(Arc<AtomicUsize>);
+
+impl LiveSocket {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for LiveSocket {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Risposta al primo frame del client: `welcome`, oppure l'errore da inviare prima di chiudere
+#[allow(clippy::result_large_err)] // come `ClientMessage::parse`, l'errore è il frame da inviare
+fn answer_hello(text: &str) -> Result<ServerMessage, ServerMessage> {
+    match ClientMessage::parse(text)? {
+        ClientMessage::Hello { protocol_version, .. } if protocol_version < MIN_PROTOCOL_VERSION => {
+            Err(ServerMessage::error(
+                ErrorCode::UnsupportedProtocolVersion,
+                format!("Protocol version {} is no longer supported (minimum {})", protocol_version, MIN_PROTOCOL_VERSION),
+            ))
+        }
+        ClientMessage::Hello { protocol_version, .. } => Ok(ServerMessage::Welcome {
+            protocol_version: protocol_version.min(PROTOCOL_VERSION),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }),
+        _ => Err(ServerMessage::error(ErrorCode::HelloRequired, "The first message must be hello")),
+    }
+}
+
+// Primo frame con dati del client (i ping/pong di controllo non contano); `None` se chiude
+async fn first_frame(receiver: &mut SplitStream<WebSocket>) -> Option<Message> {
+    while let Some(Ok(msg)) = receiver.next().await {
+        match msg {
+            Message::Ping(_) | Message::Pong(_) => continue,
+            Message::Close(_) => return None,
+            msg => return Some(msg),
+        }
+    }
+    None
+}
+
+// Handshake prima di registrare la connessione: `false` se il socket va chiuso
+async fn handshake(
+    sender: &mut SplitSink<WebSocket, Message>,
+    receiver: &mut SplitStream<WebSocket>,
+    user_id: &str,
+) -> bool {
+    let answer = match tokio::time::timeout(HELLO_TIMEOUT, first_frame(receiver)).await {
+        Ok(None) => return false,
+        Ok(Some(Message::Text(text))) => answer_hello(&text),
+        Ok(Some(_)) => Err(ServerMessage::error(ErrorCode::HelloRequired, "The first message must be hello")),
+        Err(_) => Err(ServerMessage::error(ErrorCode::HelloRequired, "No hello received in time")),
+    };
+
+    let (reply, accepted) = match answer {
+        Ok(welcome) => (welcome, true),
+        Err(error) => {
+            tracing::info!("🤝 [WebSocket] Handshake di {} fallito, chiusura connessione", user_id);
+            (error, false)
+        }
+    };
+
+    if sender.send(Message::Text(serde_json::to_string(&reply).unwrap())).await.is_err() {
+        return false;
+    }
 
-    ws.on_upgrade(move |socket| handle_socket(socket, user_id, ws_state))
+    if !accepted {
+        let _ = sender
+            .send(Message::Close(Some(CloseFrame {
+                code: CLOSE_HANDSHAKE_FAILED,
+                reason: "hello required".into(),
+            })))
+            .await;
+    }
+
+    accepted
 }
 
-async fn handle_socket(socket: WebSocket, user_id: String, ws_state: WsState) {
+async fn handle_socket(socket: WebSocket, user_id: String, session_id: String, ws_state: WsState) {
+    let _live = LiveSocket::new(&ws_state.live_sockets);
     let (mut sender, mut receiver) = socket.split();
 
-    // Crea broadcast channel per questo utente
-    let (tx, mut rx) = broadcast::channel(100);
+    // Il primo frame deve essere `hello`: fino ad allora la connessione non è registrata
+    if !handshake(&mut sender, &mut receiver, &user_id).await {
+        return;
+    }
 
-    // Registra connessione
-    {
-        let mut connections = ws_state.connections.write().await;
-        connections.insert(user_id.clone(), tx.clone());
-        tracing::info!("✅ [WebSocket] Utente {} connesso (totale: {})", user_id, connections.len());
+    // Coda di invio di questa connessione (presenza separata, scartabile)
+    let (tx, mut inbound) = send_queue::channel(ws_state.queue_metrics.clone());
+    let connection_id = Uuid::new_v4();
+
+    // Iscriviti al segnale di chiusura della sessione
+    let mut close_rx = {
+        let mut closers = ws_state.session_closers.write().await;
+        closers
+            .entry(session_id.clone())
+            .or_insert_with(|| broadcast::channel(1).0)
+            .subscribe()
+    };
+
+    // Presenza salvata (stato scelto e stato personalizzato) da ripristinare alla connessione
+    let presence = match Uuid::parse_str(&user_id) {
+        Ok(id) => presence::load(&ws_state.db, id).await.unwrap_or_else(|e| {
+            tracing::error!("❌ [WebSocket] Caricamento presenza di {} fallito: {}", user_id, e);
+            Presence::default()
+        }),
+        Err(_) => Presence::default(),
+    };
+    let expires_at = presence.custom_status.as_ref().and_then(|custom| custom.expires_at);
+
+    // Registra connessione; al primo dispositivo gli amici ricevono la presenza
+    // (niente se l'utente è invisibile o era già connesso a un altro nodo)
+    if let Some(connected_at) = ws_state.register(&user_id, connection_id, tx.clone(), presence).await {
+        ws_state.persist_presence(&user_id, true, connected_at).await;
+
+        if let (Some(expires_at), Ok(id)) = (expires_at, Uuid::parse_str(&user_id)) {
+            presence::schedule_expiry(ws_state.clone(), id, expires_at);
+        }
     }
 
-    // Notifica che l'utente è online
-    ws_state
-        .broadcast(&WsMessage::UserOnline {
-            user_id: user_id.clone(),
-        })
-        .await;
-    tracing::info!("📢 [WebSocket] Broadcast user_online per {}", user_id);
+    // Ultimo pong ricevuto, aggiornato dal task di ricezione
+    let last_pong = Arc::new(Mutex::new(Instant::now()));
+    let heartbeat = ws_state.heartbeat;
 
-    // Task per inviare messaggi al client
+    // Task per inviare messaggi al client (e i ping di heartbeat)
+    let send_last_pong = last_pong.clone();
+    let send_user_id = user_id.clone();
+    let send_metrics = ws_state.queue_metrics.clone();
+    let mut shutdown_rx = ws_state.shutdown_tx.subscribe();
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if sender.send(Message::Text(msg)).await.is_err() {
-                break;
+        let mut ping_interval = tokio::time::interval(heartbeat.interval);
+        ping_interval.tick().await;
+
+        loop {
+            // In ordine di priorità: chiusure, heartbeat, poi i frame (presenza per ultima)
+            tokio::select! {
+                biased;
+
+                _ = inbound.overflow.notified() => {
+                    tracing::info!("🐢 [WebSocket] Coda di invio di {} piena, chiusura connessione", send_user_id);
+                    send_metrics.slow_consumer_closed.fetch_add(1, Ordering::Relaxed);
+                    let _ = sender
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CLOSE_SLOW_CONSUMER,
+                            reason: "client too slow".into(),
+                        })))
+                        .await;
+                    break;
+                }
+                _ = shutdown_rx.recv() => {
+                    let restarting = ServerMessage::ServerRestarting {
+                        reconnect_after_ms: rand::thread_rng().gen_range(RECONNECT_HINT_MS),
+                    };
+                    let _ = sender.send(Message::Text(serde_json::to_string(&restarting).unwrap())).await;
+                    let _ = sender
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CLOSE_SERVICE_RESTART,
+                            reason: "server restarting".into(),
+                        })))
+                        .await;
+                    break;
+                }
+                _ = close_rx.recv() => {
+                    tracing::info!("🔒 [WebSocket] Sessione revocata, chiusura socket");
+                    let _ = sender
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CLOSE_SESSION_REVOKED,
+                            reason: "session revoked".into(),
+                        })))
+                        .await;
+                    break;
+                }
+                _ = ping_interval.tick() => {
+                    let silent_for = send_last_pong.lock().unwrap().elapsed();
+                    if silent_for > heartbeat.timeout() {
+                        tracing::info!("💀 [WebSocket] Nessun pong da {} da {:?}, chiusura connessione", send_user_id, silent_for);
+                        let _ = sender
+                            .send(Message::Close(Some(CloseFrame {
+                                code: CLOSE_HEARTBEAT_TIMEOUT,
+                                reason: "heartbeat timeout".into(),
+                            })))
+                            .await;
+                        break;
+                    }
+                    if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                        break;
+                    }
+                }
+                msg = inbound.reliable.recv() => {
+                    let Some(msg) = msg else { break };
+                    if sender.send(Message::Text(msg)).await.is_err() {
+                        break;
+                    }
+                }
+                Some(msg) = inbound.presence.recv() => {
+                    if sender.send(Message::Text(msg)).await.is_err() {
+                        break;
+                    }
+                }
             }
         }
     });
@@ -125,35 +1182,92 @@ async fn handle_socket(socket: WebSocket, user_id: String, ws_state: WsState) {
     // Task per ricevere messaggi dal client
     let user_id_clone2 = user_id.clone();
     let ws_state_clone = ws_state.clone();
+    let reply_tx = tx.clone();
+    let session_id_clone = session_id.clone();
     let mut recv_task = tokio::spawn(async move {
+        // Limite di frequenza dei segnali WebRTC per questa connessione
+        let signal_limiter = MemoryRateLimitStore::new();
+
+        // Le risposte (pong, errori, replay) vanno solo a questa connessione
+        let reply = |message: &ServerMessage| {
+            reply_tx.send(serde_json::to_string(message).unwrap(), Lane::Reliable);
+        };
+
         while let Some(Ok(msg)) = receiver.next().await {
             if let Message::Text(text) = msg {
-                // Gestisci ping/pong e WebRTC signaling
-                if let Ok(ws_msg) = serde_json::from_str::<WsMessage>(&text) {
-                    match ws_msg {
-                        WsMessage::Ping => {
-                            ws_state_clone
-                                .send_to_user(&user_id_clone2, &WsMessage::Pong)
-                                .await;
+                let client_msg = match ClientMessage::parse(&text) {
+                    Ok(client_msg) => client_msg,
+                    Err(error) => {
+                        tracing::info!("⚠️ [WebSocket] Messaggio non valido da {}", user_id_clone2);
+                        reply(&error);
+                        continue;
+                    }
+                };
+
+                match client_msg {
+                    // L'handshake è già avvenuto
+                    ClientMessage::Hello { .. } => {
+                        reply(&ServerMessage::error(ErrorCode::UnsupportedMessage, "Hello already received"));
+                    }
+                    ClientMessage::Ping => reply(&ServerMessage::Pong),
+                    ClientMessage::Resume { last_seq } => {
+                        match ws_state_clone.replay(&user_id_clone2, &session_id_clone, last_seq).await {
+                            Ok(frames) => {
+                                tracing::info!("🔁 [WebSocket] Resume di {}: {} eventi", user_id_clone2, frames.len() - 1);
+                                for frame in frames {
+                                    reply_tx.send(frame, Lane::Reliable);
+                                }
+                            }
+                            Err(e) => {
+                                tracing::error!("❌ [WebSocket] Resume di {} fallito: {}", user_id_clone2, e);
+                                reply(&ServerMessage::error(ErrorCode::ResumeFailed, "Could not replay missed events"));
+                            }
+                        }
+                    }
+                    ClientMessage::Ack { seq } => {
+                        let (Ok(user), Ok(session)) = (Uuid::parse_str(&user_id_clone2), Uuid::parse_str(&session_id_clone)) else {
+                            continue;
+                        };
+                        if let Err(e) = outbox::ack(&ws_state_clone.db, user, session, seq).await {
+                            tracing::error!("❌ [WebSocket] Ack di {} fallito: {}", user_id_clone2, e);
+                        }
+                    }
+                    ClientMessage::WebRTCSignal { to_user_id, signal } => {
+                        if let Err(error) = ws_state_clone
+                            .check_relay(&user_id_clone2, &to_user_id, &signal, &signal_limiter)
+                            .await
+                        {
+                            tracing::info!("🚫 [WebSocket] Segnale WebRTC da {} a {} rifiutato", user_id_clone2, to_user_id);
+                            reply(&error);
+                            continue;
                         }
-                        WsMessage::WebRTCSignal { from_user_id: _, to_user_id, signal } => {
-                            // Relay WebRTC signal to destination user
-                            tracing::info!("📡 [WebSocket] Relay segnale WebRTC da {} a {}", user_id_clone2, to_user_id);
-                            ws_state_clone
-                                .send_to_user(
-                                    &to_user_id,
-                                    &WsMessage::WebRTCSignal {
-                                        from_user_id: user_id_clone2.clone(),
-                                        to_user_id: to_user_id.clone(),
-                                        signal,
-                                    },
-                                )
-                                .await;
-                            tracing::info!("✅ [WebSocket] Segnale relay completato");
+
+                        // Relay WebRTC signal to destination user
+                        tracing::info!("📡 [WebSocket] Relay segnale WebRTC da {} a {}", user_id_clone2, to_user_id);
+                        ws_state_clone
+                            .send_to_user(
+                                &to_user_id,
+                                &ServerMessage::WebRTCSignal {
+                                    from_user_id: user_id_clone2.clone(),
+                                    to_user_id: to_user_id.clone(),
+                                    signal,
+                                },
+                            )
+                            .await;
+                        tracing::info!("✅ [WebSocket] Segnale relay completato");
+                    }
+                    message @ (ClientMessage::CallInvite { .. }
+                    | ClientMessage::CallAccept { .. }
+                    | ClientMessage::CallReject { .. }
+                    | ClientMessage::CallCancel { .. }
+                    | ClientMessage::CallEnd { .. }) => {
+                        if let Err(error) = calls::handle_message(&ws_state_clone, &user_id_clone2, message).await {
+                            reply(&error);
                         }
-                        _ => {}
                     }
                 }
+            } else if let Message::Pong(_) = msg {
+                *last_pong.lock().unwrap() = Instant::now();
             } else if let Message::Close(_) = msg {
                 break;
             }
@@ -161,19 +1275,204 @@ async fn handle_socket(socket: WebSocket, user_id: String, ws_state: WsState) {
     });
 
     // Attendi che uno dei task finisca
+    // (attende anche l'altro task, così le sue risorse sono rilasciate prima del cleanup)
     tokio::select! {
-        _ = (&mut send_task) => recv_task.abort(),
-        _ = (&mut recv_task) => send_task.abort(),
+        _ = (&mut send_task) => {
+            recv_task.abort();
+            let _ = recv_task.await;
+        }
+        _ = (&mut recv_task) => {
+            send_task.abort();
+            let _ = send_task.await;
+        }
     }
 
     // Rimuovi connessione
+    let offline = ws_state.unregister(&user_id, connection_id).await;
+
+    // Rimuovi il segnale di chiusura se nessun altro socket usa la sessione
     {
-        let mut connections = ws_state.connections.write().await;
-        connections.remove(&user_id);
+        let mut closers = ws_state.session_closers.write().await;
+        if closers.get(&session_id).is_some_and(|tx| tx.receiver_count() == 0) {
+            closers.remove(&session_id);
+        }
     }
 
-    // Notifica che l'utente è offline
-    ws_state
-        .broadcast(&WsMessage::UserOffline { user_id })
-        .await;
+    // Offline solo quando si chiude l'ultimo dispositivo (su tutti i nodi)
+    if let Some(at) = offline {
+        ws_state.went_offline(&user_id, at).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state() -> WsState {
+        // Il pool non si connette finché non viene usato
+        WsState::new(PgPool::connect_lazy("postgres://localhost/gamecall_test").unwrap())
+    }
+
+    fn queue(state: &WsState) -> (Outbound, send_queue::Inbound) {
+        send_queue::channel(state.queue_metrics.clone())
+    }
+
+    #[tokio::test]
+    async fn test_multi_device_presence_and_fan_out() {
+        let state = test_state();
+        let (tx1, mut rx1) = queue(&state);
+        let (tx2, mut rx2) = queue(&state);
+        let (conn1, conn2) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert!(state.register("user", conn1, tx1, Presence::default()).await.is_some());
+        assert!(state.register("user", conn2, tx2, Presence::default()).await.is_none());
+
+        state.send_to_user("user", &ServerMessage::Pong).await;
+        assert_eq!(rx1.reliable.recv().await.unwrap(), r#"{"type":"pong"}"#);
+        assert_eq!(rx2.reliable.recv().await.unwrap(), r#"{"type":"pong"}"#);
+
+        // Chiudere un dispositivo non rende l'utente offline
+        assert!(state.unregister("user", conn1).await.is_none());
+        assert!(state.is_online("user").await);
+
+        assert!(state.unregister("user", conn2).await.is_some());
+        assert!(!state.is_online("user").await);
+    }
+
+    #[test]
+    fn test_answer_hello() {
+        let Ok(ServerMessage::Welcome { protocol_version, .. }) = answer_hello(&format!(
+            r#"{{"type":"hello","protocol_version":{}}}"#,
+            PROTOCOL_VERSION + 1
+        )) else {
+            panic!("hello valido rifiutato");
+        };
+        assert_eq!(protocol_version, PROTOCOL_VERSION);
+
+        let code = |text: &str| match answer_hello(text) {
+            Err(ServerMessage::Error { code, .. }) => code,
+            other => panic!("atteso un errore, ricevuto {:?}", other),
+        };
+        assert_eq!(code(r#"{"type":"hello","protocol_version":0}"#), ErrorCode::UnsupportedProtocolVersion);
+        assert_eq!(code(r#"{"type":"ping"}"#), ErrorCode::HelloRequired);
+        assert_eq!(code("not json"), ErrorCode::MalformedMessage);
+    }
+
+    #[tokio::test]
+    async fn test_custom_status_expiry_single_task_per_user() {
+        let state = test_state();
+        let user_id = Uuid::new_v4();
+        let (first_at, second_at) = (Utc::now(), Utc::now() + chrono::Duration::minutes(1));
+
+        let (first_tx, first_rx) = tokio::sync::oneshot::channel::<()>();
+        state.spawn_custom_status_expiry(user_id, first_at, async move {
+            let _first_tx = first_tx;
+            std::future::pending::<()>().await
+        });
+        state.spawn_custom_status_expiry(user_id, second_at, std::future::pending());
+
+        // La nuova scadenza annulla il task precedente (che droppa il sender)
+        assert!(first_rx.await.is_err());
+        assert_eq!(state.pending_custom_status_expiries(), 1);
+
+        // Un task vecchio che termina non toglie quello registrato dopo
+        state.finish_custom_status_expiry(user_id, first_at);
+        assert_eq!(state.pending_custom_status_expiries(), 1);
+
+        state.cancel_custom_status_expiry(user_id);
+        assert_eq!(state.pending_custom_status_expiries(), 0);
+    }
+
+    // Attende che un evento del cluster sia stato applicato
+    async fn eventually(state: &WsState, user_id: &str, online: bool) {
+        for _ in 0..100 {
+            if state.is_online(user_id).await == online {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} non è diventato {}", user_id, if online { "online" } else { "offline" });
+    }
+
+    #[tokio::test]
+    async fn test_cluster_presence_and_delivery() {
+        let pubsub: Arc<dyn PubSub> = Arc::new(MemoryPubSub::new());
+        let node_a = test_state().with_pubsub(pubsub.clone());
+        let node_b = test_state().with_pubsub(pubsub);
+        node_a.start();
+        node_b.start();
+
+        let (tx, mut rx) = queue(&node_b);
+        let connection_id = Uuid::new_v4();
+        node_b.register("user", connection_id, tx, Presence::default()).await;
+        eventually(&node_a, "user", true).await;
+
+        // Un messaggio inviato dal nodo A arriva al dispositivo connesso al nodo B
+        node_a.send_to_user("user", &ServerMessage::Pong).await;
+        let frame = tokio::time::timeout(Duration::from_secs(1), rx.reliable.recv()).await.unwrap().unwrap();
+        assert_eq!(frame, r#"{"type":"pong"}"#);
+
+        assert!(node_b.unregister("user", connection_id).await.is_some());
+        eventually(&node_a, "user", false).await;
+    }
+
+    #[tokio::test]
+    async fn test_relay_checks() {
+        let state = test_state();
+        let limiter = MemoryRateLimitStore::new();
+        let friends = HashSet::from(["b".to_string()]);
+        state.friend_cache.write().await.insert("a".to_string(), Arc::new(friends));
+        let signal = serde_json::json!({ "type": "offer", "sdp": "v=0" });
+
+        let code = |result: Result<(), ServerMessage>| match result {
+            Err(ServerMessage::Error { code, .. }) => Some(code),
+            _ => None,
+        };
+
+        assert_eq!(code(state.check_relay("a", "c", &signal, &limiter).await), Some(ErrorCode::NotFriends));
+        assert_eq!(code(state.check_relay("a", "b", &signal, &limiter).await), Some(ErrorCode::RecipientOffline));
+
+        // Invisibile: stessa risposta di un utente offline
+        let (tx, _rx) = queue(&state);
+        let invisible = Presence {
+            status: presence::PresenceStatus::Invisible,
+            ..Presence::default()
+        };
+        state.register("b", Uuid::new_v4(), tx.clone(), invisible).await;
+        assert_eq!(code(state.check_relay("a", "b", &signal, &limiter).await), Some(ErrorCode::RecipientOffline));
+
+        state.update_presence("b", Presence::default()).await;
+        assert_eq!(code(state.check_relay("a", "b", &signal, &limiter).await), None);
+
+        let huge = serde_json::json!({ "sdp": "x".repeat(MAX_SIGNAL_BYTES) });
+        assert_eq!(code(state.check_relay("a", "b", &huge, &limiter).await), Some(ErrorCode::PayloadTooLarge));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_disconnects_remaining_users() {
+        let state = test_state();
+        let (tx, _rx) = queue(&state);
+        state.register("user", Uuid::new_v4(), tx, Presence::default()).await;
+
+        // Nessun socket reale da attendere: l'utente viene rimosso allo scadere del timeout
+        state.shutdown(Duration::from_millis(10)).await;
+        assert!(state.draining.load(Ordering::SeqCst));
+        assert!(!state.is_online("user").await);
+    }
+
+    #[tokio::test]
+    async fn test_invisible_user_appears_offline() {
+        let state = test_state();
+        let (tx, _rx) = queue(&state);
+        let presence = Presence {
+            status: presence::PresenceStatus::Invisible,
+            ..Presence::default()
+        };
+
+        state.register("user", Uuid::new_v4(), tx, presence).await;
+
+        assert!(state.is_online("user").await);
+        assert_eq!(state.visible_presence("user").await, PresenceView::offline());
+        assert_eq!(state.visible_presence("other").await, PresenceView::offline());
+    }
 }
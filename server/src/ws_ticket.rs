@@ -0,0 +1,97 @@
+use axum::{extract::State, Extension, Json};
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::Claims,
+    error::AppResult,
+    utils::{generate_token, hash_token},
+};
+
+/// Validità di un ticket: il client lo chiede subito prima di aprire il WebSocket
+const TICKET_TTL_SECS: i64 = 30;
+
+#[derive(Debug, Serialize)]
+pub struct TicketResponse {
+    pub ticket: String,
+    pub expires_in: i64,
+}
+
+/// Utente e sessione di una connessione WebSocket autenticata
+#[derive(Debug, Clone)]
+pub struct WsIdentity {
+    pub user_id: String,
+    pub session_id: String,
+}
+
+impl From<Claims> for WsIdentity {
+    fn from(claims: Claims) -> Self {
+        Self {
+            user_id: claims.sub,
+            session_id: claims.sid,
+        }
+    }
+}
+
+/// Crea un ticket monouso per `/ws?ticket=`, legato a utente e sessione del JWT
+pub async fn create_ticket(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> AppResult<Json<TicketResponse>> {
+    let user_id = claims.user_id()?;
+    let session_id = claims.session_id()?;
+
+    // Pulizia dei ticket scaduti senza essere usati
+    sqlx::query("DELETE FROM ws_tickets WHERE expires_at < NOW()")
+        .execute(&state.db)
+        .await?;
+
+    let ticket = generate_token();
+    let expires_at = Utc::now() + Duration::seconds(TICKET_TTL_SECS);
+
+    sqlx::query(
+        r#"
+        INSERT INTO ws_tickets (ticket_hash, user_id, session_id, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#
+    )
+    .bind(hash_token(&ticket))
+    .bind(user_id)
+    .bind(session_id)
+    .bind(expires_at)
+    .execute(&state.db)
+    .await?;
+
+    Ok(Json(TicketResponse {
+        ticket,
+        expires_in: TICKET_TTL_SECS,
+    }))
+}
+
+/// Consuma un ticket: valido una sola volta, se non è scaduto e la sessione è ancora attiva
+pub async fn consume(db: &PgPool, ticket: &str) -> Result<Option<WsIdentity>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (Uuid, Uuid)>(
+        r#"
+        DELETE FROM ws_tickets t
+        USING sessions s
+        WHERE t.ticket_hash = $1
+          AND t.expires_at > NOW()
+          AND s.id = t.session_id
+          AND s.revoked_at IS NULL
+          AND s.expires_at > NOW()
+        RETURNING t.user_id, t.session_id
+        "#
+    )
+    .bind(hash_token(ticket))
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|(user_id, session_id)| WsIdentity {
+        user_id: user_id.to_string(),
+        session_id: session_id.to_string(),
+    }))
+}
@@ -4,12 +4,18 @@ fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
 
+// Versione del protocollo WebSocket con cui è stata compilata l'app
+#[tauri::command]
+fn protocol_version() -> u32 {
+    gamecall_protocol::PROTOCOL_VERSION
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_fs::init())
-        .invoke_handler(tauri::generate_handler![greet])
+        .invoke_handler(tauri::generate_handler![greet, protocol_version])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
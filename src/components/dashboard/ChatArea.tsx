@@ -1,29 +1,35 @@
 import { useState, useEffect, useCallback } from 'react';
 import { Contact } from '../../types';
 import { useWebRTC, Message } from '../../hooks/useWebRTC';
-import { WsMessage } from '../../hooks/useWebSocket';
+import { ClientMessage } from '../../hooks/useWebSocket';
+import { useMessages } from '../../hooks/useMessages';
+import type { DirectMessage } from '../../types/protocol/DirectMessage';
 
 interface ChatAreaProps {
   selectedContact: Contact | null;
   onRemoveFriend: (friendId: string) => void;
-  sendWsMessage: (message: WsMessage) => void;
+  sendWsMessage: (message: ClientMessage) => void;
   webrtcRef: React.MutableRefObject<ReturnType<typeof useWebRTC> | null>;
+  // Ultimo message_new ricevuto dal WebSocket
+  incomingMessage: DirectMessage | null;
 }
 
-export function ChatArea({ selectedContact, onRemoveFriend, sendWsMessage, webrtcRef }: ChatAreaProps) {
+export function ChatArea({ selectedContact, onRemoveFriend, sendWsMessage, webrtcRef, incomingMessage }: ChatAreaProps) {
   const [message, setMessage] = useState('');
-  const [messages, setMessages] = useState<Message[]>([]);
+
+  // Messaggi salvati sul server: restano anche se uno dei due è offline
+  const chat = useMessages(selectedContact?.id ?? null);
+  const { messages, append, receive } = chat;
 
   // WebRTC per messaggi P2P
   const webrtc = useWebRTC({
     contactId: selectedContact?.id || '',
     onMessageReceived: useCallback((msg: Message) => {
-      setMessages(prev => [...prev, msg]);
-    }, []),
+      append([msg]);
+    }, [append]),
     sendSignal: useCallback((toUserId: string, signal: any) => {
       sendWsMessage({
         type: 'webrtc_signal',
-        from_user_id: '', // Viene impostato dal server
         to_user_id: toUserId,
         signal,
       });
@@ -33,10 +39,11 @@ export function ChatArea({ selectedContact, onRemoveFriend, sendWsMessage, webrt
   // Salva ref per callback WebSocket dal Dashboard
   webrtcRef.current = webrtc;
 
-  // Reset messages quando cambia contatto
   useEffect(() => {
-    setMessages([]);
-  }, [selectedContact?.id]);
+    if (incomingMessage) {
+      receive(incomingMessage);
+    }
+  }, [incomingMessage, receive]);
 
   // Auto-connetti quando selezionato contatto online
   useEffect(() => {
@@ -46,27 +53,17 @@ export function ChatArea({ selectedContact, onRemoveFriend, sendWsMessage, webrt
     }
   }, [selectedContact, webrtc]);
 
-  const handleSendMessage = useCallback((e: React.FormEvent) => {
+  const handleSendMessage = useCallback(async (e: React.FormEvent) => {
     e.preventDefault();
     if (!message.trim() || !selectedContact) return;
 
-    const sent = webrtc.sendMessage(message.trim());
+    const sent = await chat.send(message.trim());
     if (sent) {
-      // Aggiungi messaggio locale
-      setMessages(prev => [...prev, {
-        id: crypto.randomUUID(),
-        senderId: 'me',
-        content: message.trim(),
-        timestamp: new Date(),
-        isMe: true,
-      }]);
       setMessage('');
     } else {
-      // TODO: Fallback a server se P2P non disponibile
-      console.warn('⚠️ [Chat] P2P non disponibile, messaggio non inviato');
-      alert('Connessione P2P non disponibile. Assicurati che entrambi siate online.');
+      alert('Invio del messaggio non riuscito. Riprova.');
     }
-  }, [message, selectedContact, webrtc]);
+  }, [message, selectedContact, chat]);
 
   // Stato vuoto - nessun contatto selezionato
   if (!selectedContact) {
@@ -175,6 +172,16 @@ export function ChatArea({ selectedContact, onRemoveFriend, sendWsMessage, webrt
 
       {/* Area messaggi */}
       <div className="flex-1 overflow-y-auto p-6 space-y-4">
+        {chat.hasOlder && (
+          <div className="text-center">
+            <button
+              onClick={chat.loadOlder}
+              className="text-xs text-primary-500 hover:text-primary-600"
+            >
+              Carica messaggi precedenti
+            </button>
+          </div>
+        )}
         {messages.length === 0 ? (
           <div className="flex flex-col items-center justify-center h-full text-center">
             <svg className="w-20 h-20 text-gray-300 dark:text-gray-700 mb-4" fill="none" stroke="currentColor" viewBox="0 0 24 24">
@@ -182,7 +189,7 @@ export function ChatArea({ selectedContact, onRemoveFriend, sendWsMessage, webrt
             </svg>
             <p className="text-gray-500 dark:text-gray-400">Nessun messaggio ancora</p>
             <p className="text-sm text-gray-400 dark:text-gray-500 mt-1">
-              {webrtc.isConnected ? 'Inizia una conversazione P2P criptata!' : 'Connetti P2P per iniziare'}
+              {chat.isLoading ? 'Caricamento...' : 'Inizia una conversazione!'}
             </p>
           </div>
         ) : (
@@ -212,30 +219,24 @@ export function ChatArea({ selectedContact, onRemoveFriend, sendWsMessage, webrt
 
       {/* Input messaggio */}
       <div className="bg-white dark:bg-gray-800 border-t border-gray-200 dark:border-gray-700 p-4">
-        {!webrtc.isConnected ? (
-          <div className="text-center text-sm text-gray-500 dark:text-gray-400">
-            Connetti P2P per inviare messaggi criptati
-          </div>
-        ) : (
-          <form onSubmit={handleSendMessage} className="flex gap-3">
-            <input
-              type="text"
-              value={message}
-              onChange={(e) => setMessage(e.target.value)}
-              placeholder="Scrivi un messaggio P2P..."
-              className="flex-1 px-4 py-3 bg-gray-100 dark:bg-gray-700 border-0 rounded-full text-sm text-gray-900 dark:text-white placeholder-gray-500 dark:placeholder-gray-400 focus:outline-none focus:ring-2 focus:ring-primary-500"
-            />
-            <button
-              type="submit"
-              disabled={!message.trim()}
-              className="px-6 py-3 bg-primary-500 hover:bg-primary-600 disabled:bg-gray-300 dark:disabled:bg-gray-600 text-white rounded-full font-medium transition-colors disabled:cursor-not-allowed"
-            >
-              <svg className="w-5 h-5" fill="none" stroke="currentColor" viewBox="0 0 24 24">
-                <path strokeLinecap="round" strokeLinejoin="round" strokeWidth={2} d="M12 19l9 2-9-18-9 18 9-2zm0 0v-8" />
-              </svg>
-            </button>
-          </form>
-        )}
+        <form onSubmit={handleSendMessage} className="flex gap-3">
+          <input
+            type="text"
+            value={message}
+            onChange={(e) => setMessage(e.target.value)}
+            placeholder="Scrivi un messaggio..."
+            className="flex-1 px-4 py-3 bg-gray-100 dark:bg-gray-700 border-0 rounded-full text-sm text-gray-900 dark:text-white placeholder-gray-500 dark:placeholder-gray-400 focus:outline-none focus:ring-2 focus:ring-primary-500"
+          />
+          <button
+            type="submit"
+            disabled={!message.trim()}
+            className="px-6 py-3 bg-primary-500 hover:bg-primary-600 disabled:bg-gray-300 dark:disabled:bg-gray-600 text-white rounded-full font-medium transition-colors disabled:cursor-not-allowed"
+          >
+            <svg className="w-5 h-5" fill="none" stroke="currentColor" viewBox="0 0 24 24">
+              <path strokeLinecap="round" strokeLinejoin="round" strokeWidth={2} d="M12 19l9 2-9-18-9 18 9-2zm0 0v-8" />
+            </svg>
+          </button>
+        </form>
       </div>
     </div>
   );
@@ -8,6 +8,7 @@ import { Sidebar } from './Sidebar';
 import { ChatArea } from './ChatArea';
 import { ProfilePanel } from './ProfilePanel';
 import { NotesPanel } from './NotesPanel';
+import type { DirectMessage } from '../../types/protocol/DirectMessage';
 
 export function NewDashboard() {
   const { user, logout } = useAuth();
@@ -15,12 +16,18 @@ export function NewDashboard() {
   const [selectedContact, setSelectedContact] = useState<Contact | null>(null);
   const [showProfile, setShowProfile] = useState(false);
   const [showNotes, setShowNotes] = useState(false);
+  const [incomingMessage, setIncomingMessage] = useState<DirectMessage | null>(null);
 
   // Ref per webrtc (per evitare dipendenze circolari)
   const webrtcRef = useRef<ReturnType<typeof useWebRTC> | null>(null);
 
   // WebSocket per aggiornamenti real-time (UNICA istanza per tutta l'app!)
   const { sendMessage: sendWsMessage } = useWebSocket({
+    onMessage: (message) => {
+      if (message.type === 'message_new') {
+        setIncomingMessage(message.message);
+      }
+    },
     onFriendAdded: (_friendId, friendUsername, _friendCode) => {
       console.log('✅ [WebSocket] Nuovo amico aggiunto:', friendUsername);
       loadFriends();
@@ -91,6 +98,7 @@ export function NewDashboard() {
           onRemoveFriend={handleRemoveFriend}
           sendWsMessage={sendWsMessage}
           webrtcRef={webrtcRef}
+          incomingMessage={incomingMessage}
         />
       )}
 
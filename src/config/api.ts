@@ -31,6 +31,28 @@ export const API_ENDPOINTS = {
   friendRequests: `${API_BASE_URL}/friends/requests`,
   acceptFriend: `${API_BASE_URL}/friends/accept`,
   rejectFriend: `${API_BASE_URL}/friends/reject`,
+
+  // Messaggi diretti
+  messages: `${API_BASE_URL}/messages`,
+  conversations: `${API_BASE_URL}/conversations`,
+
+  // WebSocket
+  wsTicket: `${API_BASE_URL}/ws/ticket`,
 };
 
+// Body degli errori restituiti dal backend
+export interface ApiError {
+  code: string;
+  message: string;
+  details: Record<string, unknown> | null;
+}
+
+export async function readApiError(response: Response): Promise<ApiError> {
+  try {
+    return await response.json();
+  } catch {
+    return { code: 'UNKNOWN', message: response.statusText, details: null };
+  }
+}
+
 export default API_BASE_URL;
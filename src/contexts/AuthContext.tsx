@@ -1,6 +1,6 @@
 import { createContext, useContext, useState, useEffect, ReactNode } from 'react';
 import { User, AuthState } from '../types';
-import { API_ENDPOINTS } from '../config/api';
+import { API_ENDPOINTS, readApiError } from '../config/api';
 
 interface AuthContextType {
   user: User | null;
@@ -66,8 +66,8 @@ export function AuthProvider({ children }: { children: ReactNode }) {
       });
 
       if (!response.ok) {
-        const errorData = await response.text();
-        throw new Error(errorData || 'Errore durante il login');
+        const error = await readApiError(response);
+        throw new Error(error.message || 'Errore durante il login');
       }
 
       const data = await response.json();
@@ -156,8 +156,8 @@ export function AuthProvider({ children }: { children: ReactNode }) {
       });
 
       if (!response.ok) {
-        const errorData = await response.text();
-        throw new Error(errorData || 'Errore durante la registrazione');
+        const error = await readApiError(response);
+        throw new Error(error.message || 'Errore durante la registrazione');
       }
 
       const data = await response.json();
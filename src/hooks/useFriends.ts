@@ -1,6 +1,6 @@
 import { useState, useEffect } from 'react';
 import { Contact } from '../types';
-import { API_ENDPOINTS } from '../config/api';
+import { API_ENDPOINTS, readApiError } from '../config/api';
 import { useAuth } from '../contexts/AuthContext';
 
 export function useFriends() {
@@ -32,11 +32,12 @@ export function useFriends() {
 
       const data = await response.json();
 
-      // Il backend restituisce array di: { id, username, friend_code, avatar_url, status }
+      // Il backend restituisce array di: { id, username, friend_code, avatar_url, status, last_seen }
       const friendsList: Contact[] = data.map((friend: any) => ({
         id: friend.id,
         username: friend.username,
         status: friend.status || 'offline',
+        lastSeen: friend.last_seen ? new Date(friend.last_seen) : undefined,
         avatar: friend.avatar_url || `https://api.dicebear.com/7.x/avataaars/svg?seed=${friend.username}`,
         friendCode: friend.friend_code,
       }));
@@ -69,15 +70,15 @@ export function useFriends() {
       });
 
       if (!response.ok) {
-        const errorText = await response.text();
+        const error = await readApiError(response);
 
         // Traduci errori backend in italiano
-        let errorMessage = errorText;
-        if (errorText.includes('Cannot add yourself')) {
+        let errorMessage = error.message;
+        if (error.code === 'CANNOT_ADD_SELF') {
           errorMessage = 'Non puoi aggiungere te stesso come amico!';
-        } else if (errorText.includes('already exists') || errorText.includes('CONFLICT')) {
+        } else if (error.code === 'FRIENDSHIP_EXISTS') {
           errorMessage = 'Hai già aggiunto questo amico!';
-        } else if (errorText.includes('not found')) {
+        } else if (error.code === 'FRIEND_CODE_NOT_FOUND') {
           errorMessage = 'Friend Code non valido o inesistente';
         }
 
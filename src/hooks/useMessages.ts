@@ -0,0 +1,132 @@
+import { useState, useEffect, useCallback } from 'react';
+import { API_ENDPOINTS, readApiError } from '../config/api';
+import { useAuth } from '../contexts/AuthContext';
+import type { DirectMessage } from '../types/protocol/DirectMessage';
+import type { Message } from './useWebRTC';
+
+// Messaggi diretti salvati sul server (storico, invio e consegna via WebSocket)
+export function useMessages(friendId: string | null) {
+  const { token, user } = useAuth();
+  const [messages, setMessages] = useState<Message[]>([]);
+  const [nextCursor, setNextCursor] = useState<string | null>(null);
+  const [isLoading, setIsLoading] = useState(false);
+
+  const toMessage = useCallback((message: DirectMessage): Message => ({
+    id: message.id,
+    senderId: message.sender_id,
+    content: message.content,
+    timestamp: new Date(message.created_at),
+    isMe: message.sender_id === user?.id,
+  }), [user?.id]);
+
+  // Aggiunge messaggi evitando duplicati (risposta del POST + message_new dello stesso messaggio)
+  const append = useCallback((incoming: Message[]) => {
+    setMessages(prev => {
+      const known = new Set(prev.map(m => m.id));
+      return [...prev, ...incoming.filter(m => !known.has(m.id))];
+    });
+  }, []);
+
+  const markRead = useCallback(async () => {
+    if (!token || !friendId) return;
+    await fetch(`${API_ENDPOINTS.conversations}/${friendId}/read`, {
+      method: 'POST',
+      headers: { 'Authorization': `Bearer ${token}` },
+    });
+  }, [token, friendId]);
+
+  const fetchPage = useCallback(async (before: string | null) => {
+    if (!token || !friendId) return null;
+
+    const params = before ? `?before=${before}` : '';
+    const response = await fetch(`${API_ENDPOINTS.conversations}/${friendId}/messages${params}`, {
+      headers: { 'Authorization': `Bearer ${token}` },
+    });
+    if (!response.ok) {
+      const error = await readApiError(response);
+      throw new Error(error.message);
+    }
+    return await response.json() as { messages: DirectMessage[]; next_cursor: string | null };
+  }, [token, friendId]);
+
+  // Carica l'ultima pagina quando cambia conversazione
+  useEffect(() => {
+    setMessages([]);
+    setNextCursor(null);
+    if (!friendId) return;
+
+    let cancelled = false;
+    setIsLoading(true);
+    fetchPage(null)
+      .then(page => {
+        if (cancelled || !page) return;
+        setMessages(page.messages.map(toMessage));
+        setNextCursor(page.next_cursor);
+        markRead();
+      })
+      .catch(err => console.error('❌ [Chat] Errore caricamento messaggi:', err))
+      .finally(() => !cancelled && setIsLoading(false));
+
+    return () => {
+      cancelled = true;
+    };
+  }, [friendId, fetchPage, toMessage, markRead]);
+
+  // Pagina precedente (messaggi più vecchi)
+  const loadOlder = useCallback(async () => {
+    if (!nextCursor) return;
+    try {
+      const page = await fetchPage(nextCursor);
+      if (!page) return;
+      setMessages(prev => [...page.messages.map(toMessage), ...prev]);
+      setNextCursor(page.next_cursor);
+    } catch (err) {
+      console.error('❌ [Chat] Errore caricamento messaggi:', err);
+    }
+  }, [nextCursor, fetchPage, toMessage]);
+
+  const send = useCallback(async (content: string): Promise<boolean> => {
+    if (!token || !friendId) return false;
+
+    try {
+      const response = await fetch(API_ENDPOINTS.messages, {
+        method: 'POST',
+        headers: {
+          'Content-Type': 'application/json',
+          'Authorization': `Bearer ${token}`,
+        },
+        body: JSON.stringify({ recipient_id: friendId, content }),
+      });
+      if (!response.ok) {
+        const error = await readApiError(response);
+        throw new Error(error.message);
+      }
+      append([toMessage(await response.json())]);
+      return true;
+    } catch (err) {
+      console.error('❌ [Chat] Errore invio messaggio:', err);
+      return false;
+    }
+  }, [token, friendId, append, toMessage]);
+
+  // Evento message_new dal WebSocket
+  const receive = useCallback((message: DirectMessage) => {
+    const peer = message.sender_id === user?.id ? message.recipient_id : message.sender_id;
+    if (peer !== friendId) return;
+
+    append([toMessage(message)]);
+    if (message.sender_id === friendId) {
+      markRead();
+    }
+  }, [user?.id, friendId, append, toMessage, markRead]);
+
+  return {
+    messages,
+    hasOlder: nextCursor !== null,
+    isLoading,
+    loadOlder,
+    send,
+    receive,
+    append,
+  };
+}
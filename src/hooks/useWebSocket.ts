@@ -1,23 +1,40 @@
 import { useEffect, useRef, useCallback } from 'react';
 import { useAuth } from '../contexts/AuthContext';
+import { API_ENDPOINTS, readApiError } from '../config/api';
+import type { ClientMessage } from '../types/protocol/ClientMessage';
+import type { ServerMessage } from '../types/protocol/ServerMessage';
 
-// Tipi dei messaggi WebSocket
-export type WsMessage =
-  | { type: 'friend_added'; friend_id: string; friend_username: string; friend_code: string }
-  | { type: 'friend_removed'; friend_id: string }
-  | { type: 'user_online'; user_id: string }
-  | { type: 'user_offline'; user_id: string }
-  | { type: 'webrtc_signal'; from_user_id: string; to_user_id: string; signal: any }
-  | { type: 'ping' }
-  | { type: 'pong' };
+// Tipi dei messaggi WebSocket, generati dal crate protocol (cd protocol && cargo test)
+export type { ClientMessage, ServerMessage };
+
+// Deve corrispondere a PROTOCOL_VERSION in protocol/src/lib.rs
+const PROTOCOL_VERSION = 2;
+const CAPABILITIES = ['presence', 'calls', 'webrtc_relay', 'resume', 'heartbeat', 'messages'];
+
+// Ticket monouso per aprire il WebSocket: il JWT resta nell'header e non finisce
+// negli URL (log del proxy, cronologia)
+async function fetchWsTicket(token: string): Promise<string> {
+  const response = await fetch(API_ENDPOINTS.wsTicket, {
+    method: 'POST',
+    headers: { Authorization: `Bearer ${token}` },
+  });
+  if (!response.ok) {
+    const error = await readApiError(response);
+    throw new Error(error.message);
+  }
+  const { ticket } = await response.json();
+  return ticket;
+}
 
 interface UseWebSocketOptions {
-  onMessage?: (message: WsMessage) => void;
+  onMessage?: (message: ServerMessage) => void;
   onFriendAdded?: (friendId: string, friendUsername: string, friendCode: string) => void;
   onFriendRemoved?: (friendId: string) => void;
   onUserOnline?: (userId: string) => void;
   onUserOffline?: (userId: string) => void;
   onWebRTCSignal?: (fromUserId: string, signal: any) => void;
+  // Eventi persi non più recuperabili: ricaricare amici e richieste via REST
+  onResync?: () => void;
 }
 
 export function useWebSocket(options: UseWebSocketOptions = {}) {
@@ -26,6 +43,14 @@ export function useWebSocket(options: UseWebSocketOptions = {}) {
   const reconnectTimeoutRef = useRef<NodeJS.Timeout | null>(null);
   const reconnectAttempts = useRef(0);
   const maxReconnectAttempts = 5;
+  // Ultimo evento durevole (con seq) ricevuto, per il resume dopo una riconnessione
+  const lastSeqRef = useRef<number | null>(null);
+  // Seq ricevuti su questa connessione: replay ed eventi live possono sovrapporsi
+  const seenSeqsRef = useRef<Set<number>>(new Set());
+  // Attesa suggerita dal server prima di riconnettersi (server_restarting)
+  const restartDelayRef = useRef<number | null>(null);
+  // Incrementato da disconnect: una connessione in attesa del ticket non va più aperta
+  const connectGenerationRef = useRef(0);
 
   // Usa ref per le callback per evitare dipendenze che cambiano
   const optionsRef = useRef(options);
@@ -33,14 +58,42 @@ export function useWebSocket(options: UseWebSocketOptions = {}) {
     optionsRef.current = options;
   }, [options]);
 
-  const connect = useCallback(() => {
+  const send = (ws: WebSocket, message: ClientMessage) => {
+    ws.send(JSON.stringify(message));
+  };
+
+  const connect = useCallback(async () => {
     if (!token) return;
+    const generation = connectGenerationRef.current;
+
+    // Tenta riconnessione con backoff esponenziale
+    const retry = () => {
+      if (reconnectAttempts.current < maxReconnectAttempts) {
+        const delay = Math.min(1000 * Math.pow(2, reconnectAttempts.current), 30000);
+        console.log(`[WebSocket] Riconnessione in ${delay}ms...`);
+
+        reconnectTimeoutRef.current = setTimeout(() => {
+          reconnectAttempts.current++;
+          connect();
+        }, delay);
+      }
+    };
+
+    let ticket: string;
+    try {
+      ticket = await fetchWsTicket(token);
+    } catch (err) {
+      console.error('[WebSocket] Ticket non ottenuto:', err);
+      retry();
+      return;
+    }
+    if (generation !== connectGenerationRef.current) return;
 
     // Usa sempre backend produzione (su Fly.io) sia in dev che in prod
     const baseUrl = 'wss://gamecall-api.fly.dev/ws';
 
-    // Aggiungi token come query parameter (non supportato nei WebSocket header)
-    const wsUrl = `${baseUrl}?token=${token}`;
+    // Il browser non permette header sul WebSocket: il ticket va nella query string
+    const wsUrl = `${baseUrl}?ticket=${encodeURIComponent(ticket)}`;
 
     try {
       const ws = new WebSocket(wsUrl);
@@ -48,19 +101,55 @@ export function useWebSocket(options: UseWebSocketOptions = {}) {
       ws.onopen = () => {
         console.log('[WebSocket] Connesso');
         reconnectAttempts.current = 0;
+
+        // Handshake, poi chiede gli eventi persi (senza last_seq il server usa
+        // l'ultimo ack della sessione)
+        seenSeqsRef.current = new Set();
+        send(ws, { type: 'hello', protocol_version: PROTOCOL_VERSION, capabilities: CAPABILITIES });
+        send(ws, { type: 'resume', last_seq: lastSeqRef.current });
       };
 
       ws.onmessage = (event) => {
         try {
-          const message: WsMessage = JSON.parse(event.data);
+          const message: ServerMessage & { seq?: number } = JSON.parse(event.data);
           console.log('[WebSocket] Messaggio ricevuto:', message);
 
-          // Gestisci ping/pong per keepalive
-          if (message.type === 'ping') {
-            ws.send(JSON.stringify({ type: 'pong' }));
+          // Eventi durevoli: scarta i duplicati del replay e conferma la ricezione
+          if (message.seq !== undefined) {
+            if (seenSeqsRef.current.has(message.seq)) {
+              return;
+            }
+            seenSeqsRef.current.add(message.seq);
+            lastSeqRef.current = Math.max(lastSeqRef.current ?? 0, message.seq);
+            send(ws, { type: 'ack', seq: lastSeqRef.current });
+          }
+
+          if (message.type === 'resumed') {
+            if (message.reset) {
+              optionsRef.current.onResync?.();
+            }
+            if (lastSeqRef.current === null || message.last_seq > lastSeqRef.current) {
+              lastSeqRef.current = message.last_seq;
+              send(ws, { type: 'ack', seq: message.last_seq });
+            }
             return;
           }
 
+          if (message.type === 'welcome') {
+            console.log(`[WebSocket] Protocollo v${message.protocol_version}`, message.capabilities);
+            return;
+          }
+
+          if (message.type === 'server_restarting') {
+            console.log(`[WebSocket] Server in riavvio, riconnessione tra ${message.reconnect_after_ms}ms`);
+            restartDelayRef.current = message.reconnect_after_ms;
+            return;
+          }
+
+          if (message.type === 'error') {
+            console.warn(`[WebSocket] Errore ${message.code}: ${message.message}`);
+          }
+
           // Callback generica
           optionsRef.current.onMessage?.(message);
 
@@ -99,16 +188,16 @@ export function useWebSocket(options: UseWebSocketOptions = {}) {
         console.log('[WebSocket] Disconnesso');
         wsRef.current = null;
 
-        // Tenta riconnessione con backoff esponenziale
-        if (reconnectAttempts.current < maxReconnectAttempts) {
-          const delay = Math.min(1000 * Math.pow(2, reconnectAttempts.current), 30000);
-          console.log(`[WebSocket] Riconnessione in ${delay}ms...`);
-
-          reconnectTimeoutRef.current = setTimeout(() => {
-            reconnectAttempts.current++;
-            connect();
-          }, delay);
+        // Riavvio del server: non è un errore, riconnetti dopo l'attesa suggerita
+        const restartDelay = restartDelayRef.current;
+        if (restartDelay !== null) {
+          restartDelayRef.current = null;
+          reconnectAttempts.current = 0;
+          reconnectTimeoutRef.current = setTimeout(connect, restartDelay);
+          return;
         }
+
+        retry();
       };
 
       wsRef.current = ws;
@@ -118,6 +207,8 @@ export function useWebSocket(options: UseWebSocketOptions = {}) {
   }, [token]);
 
   const disconnect = useCallback(() => {
+    connectGenerationRef.current++;
+
     if (reconnectTimeoutRef.current) {
       clearTimeout(reconnectTimeoutRef.current);
       reconnectTimeoutRef.current = null;
@@ -129,7 +220,7 @@ export function useWebSocket(options: UseWebSocketOptions = {}) {
     }
   }, []);
 
-  const sendMessage = useCallback((message: WsMessage) => {
+  const sendMessage = useCallback((message: ClientMessage) => {
     if (wsRef.current?.readyState === WebSocket.OPEN) {
       wsRef.current.send(JSON.stringify(message));
     } else {
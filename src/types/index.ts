@@ -4,7 +4,7 @@ export interface User {
   id: string;
   username: string;
   avatar?: string;
-  status: 'online' | 'offline' | 'in-call' | 'busy';
+  status: 'online' | 'offline' | 'idle' | 'dnd' | 'in-call' | 'busy';
   lastSeen?: Date;
   friendCode?: string;
 }
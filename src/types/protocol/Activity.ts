@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Attività in corso (gioco)
+ */
+export type Activity = { game: string, started_at: string, party_size: number | null, };
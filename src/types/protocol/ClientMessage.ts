@@ -0,0 +1,8 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { CallType } from "./CallType";
+import type { JsonValue } from "./serde_json/JsonValue";
+
+/**
+ * Messaggi inviati dal client al server
+ */
+export type ClientMessage = { "type": "hello", protocol_version: number, capabilities?: Array<string>, } | { "type": "resume", last_seq?: number | null, } | { "type": "ack", seq: number, } | { "type": "webrtc_signal", to_user_id: string, signal: JsonValue, } | { "type": "call_invite", to_user_id: string, call_type: CallType, } | { "type": "call_accept", call_id: string, } | { "type": "call_reject", call_id: string, } | { "type": "call_cancel", call_id: string, } | { "type": "call_end", call_id: string, } | { "type": "ping" };
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Messaggio diretto tra due amici, salvato sul server
+ */
+export type DirectMessage = { id: string, sender_id: string, recipient_id: string, content: string, created_at: string, read_at: string | null, };
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Codici dei frame `error`
+ */
+export type ErrorCode = "MALFORMED_MESSAGE" | "UNSUPPORTED_MESSAGE" | "UNSUPPORTED_PROTOCOL_VERSION" | "HELLO_REQUIRED" | "NOT_FRIENDS" | "RECIPIENT_OFFLINE" | "PAYLOAD_TOO_LARGE" | "RATE_LIMITED" | "RESUME_FAILED" | "CALLER_BUSY";
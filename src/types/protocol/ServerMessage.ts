@@ -0,0 +1,12 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Activity } from "./Activity";
+import type { CallType } from "./CallType";
+import type { CustomStatus } from "./CustomStatus";
+import type { DirectMessage } from "./DirectMessage";
+import type { ErrorCode } from "./ErrorCode";
+import type { JsonValue } from "./serde_json/JsonValue";
+
+/**
+ * Messaggi inviati dal server al client
+ */
+export type ServerMessage = { "type": "welcome", protocol_version: number, capabilities: Array<string>, } | { "type": "friend_added", friend_id: string, friend_username: string, friend_code: string, } | { "type": "friend_request", from_user_id: string, from_username: string, from_friend_code: string, from_avatar_url: string | null, } | { "type": "friend_request_accepted", friend_id: string, friend_username: string, friend_code: string, } | { "type": "friend_request_rejected", user_id: string, } | { "type": "friend_request_cancelled", from_user_id: string, } | { "type": "friend_removed", friend_id: string, } | { "type": "user_online", user_id: string, } | { "type": "user_offline", user_id: string, } | { "type": "presence_update", user_id: string, status: 'online' | 'offline' | 'idle' | 'dnd' | 'in-call', custom_status: CustomStatus | null, activity: Activity | null, } | { "type": "webrtc_signal", from_user_id: string, to_user_id: string, signal: JsonValue, } | { "type": "call_invite", call_id: string, from_user_id: string, to_user_id: string, call_type: CallType, } | { "type": "call_ringing", call_id: string, to_user_id: string, call_type: CallType, } | { "type": "call_accept", call_id: string, } | { "type": "call_reject", call_id: string, } | { "type": "call_cancel", call_id: string, } | { "type": "call_end", call_id: string, duration: number | null, } | { "type": "call_busy", call_id: string, to_user_id: string, } | { "type": "call_timeout", call_id: string, } | { "type": "call_unavailable", call_id: string, to_user_id: string, } | { "type": "missed_call", call_id: string, from_user_id: string, call_type: CallType, started_at: string, } | { "type": "message_new", message: DirectMessage, } | { "type": "resumed", last_seq: number, reset: boolean, } | { "type": "server_restarting", reconnect_after_ms: number, } | { "type": "error", code: ErrorCode, message: string, } | { "type": "pong" };